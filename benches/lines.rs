@@ -0,0 +1,232 @@
+//! Compares the line reader with the one it replaced, which copied
+//! every line into a new `String`. Run with `cargo bench --bench lines`.
+
+#[allow(dead_code)]
+#[path = "../src/buffered_reader.rs"]
+mod buffered_reader;
+
+use std::io::BufReader;
+use std::time::{Duration,Instant};
+
+use buffered_reader::BufferedReader;
+use legacy::LegacyReader;
+
+const LINES: usize = 500_000;
+const ROUNDS: usize = 5;
+
+/// A deterministic corpus of lines from empty to a few hundred bytes.
+fn corpus(eol: &str) -> String {
+    let mut seed = 0x2545_f491_4f6c_dd1du64;
+    let mut text = String::with_capacity(LINES * 60);
+    for line in 0..LINES {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        for word in 0..seed % 40 {
+            text.push_str(if word % 3 == 0 { "lorem " } else { "ipsum\r" });
+        }
+        text.push_str(&format!("{}", line));
+        text.push_str(eol);
+    }
+    text
+}
+
+/// the fastest of a few rounds, with the number of bytes in all lines
+fn time<F: Fn() -> usize>(f: F) -> (Duration, usize) {
+    let mut best = Duration::MAX;
+    let mut bytes = 0;
+    for _ in 0..ROUNDS {
+        let start = Instant::now();
+        bytes = f();
+        best = best.min(start.elapsed());
+    }
+    (best, bytes)
+}
+
+fn main() {
+    for &(name, eol) in &[("unix", "\n"), ("windows", "\r\n")] {
+        let text = corpus(eol);
+        let term: &'static [u8] = if eol == "\n" { b"\n" } else { b"\r\n" };
+        let (old, old_bytes) = time(|| {
+            LegacyReader::new(BufReader::new(text.as_bytes()), term)
+                .map(|res| res.unwrap().0.len())
+                .sum()
+        });
+        let (new, new_bytes) = time(|| {
+            let mut reader = BufferedReader::new(BufReader::new(text.as_bytes()), term);
+            let mut bytes = 0;
+            while let Some(res) = reader.next_line() {
+                bytes += res.unwrap().0.len();
+            }
+            bytes
+        });
+        // both see the same lines
+        assert_eq!(old_bytes, new_bytes);
+
+        let mb = text.len() as f64 / (1024.0 * 1024.0);
+        println!("{} EOL, {} lines ({:.1} MiB)", name, LINES, mb);
+        println!("String per line:   {:>10.2?} {:>8.1} MiB/s", old, mb / old.as_secs_f64());
+        println!("borrowed lines:    {:>10.2?} {:>8.1} MiB/s", new, mb / new.as_secs_f64());
+        println!("speedup:           {:>10.1}x", old.as_secs_f64() / new.as_secs_f64());
+    }
+}
+
+/// `BufferedReader` before lines were borrowed from its buffer.
+mod legacy {
+    use std::collections::VecDeque;
+    use std::io::{self,Read,BufReader,BufRead};
+
+    use crate::buffered_reader::SplitIterator;
+
+    pub struct LegacyReader<R: Read> {
+        buffer: Vec<u8>,
+        stack: VecDeque<io::Result<(String,bool)>>,
+        reader: BufReader<R>,
+        eol: &'static [u8],
+        end: bool,
+    }
+
+    impl<R: Read> Iterator for LegacyReader<R> {
+        type Item=io::Result<(String,bool)>;
+        fn next(&mut self) -> Option<Self::Item> {
+            loop {
+                if self.end && self.stack.is_empty() && self.buffer.is_empty() {
+                    // we are the EOF
+                    // no more lines/errors
+                    // no more buffered data
+                    //
+                    // iteration is over
+                    return None;
+                }
+
+                match self.stack.pop_front() {
+                    Option::None => {
+                        self.read_new_lines();
+                        continue;
+                    }
+                    Option::Some(x) => {
+                        return Some(x);
+                    }
+                };
+            }
+        }
+    }
+
+    impl<R: Read> LegacyReader<R> {
+
+        pub fn new(buffer: BufReader<R>, eol: &'static [u8]) -> LegacyReader<R> {
+            LegacyReader {
+                buffer: Vec::new(),
+                stack: VecDeque::new(),
+                reader: buffer,
+                eol,
+                end: false
+            }
+        }
+
+        fn read_new_lines(&mut self) {
+            loop {
+                if self.end && self.stack.is_empty() && self.buffer.is_empty() {
+                    // nothing more to iterate
+                    // iterator is over
+                    return;
+                }
+                if !self.stack.is_empty() {
+                    // there are lines to return
+                    return;
+                }
+                self.read_until_approx_eol();    
+                self.populate_lines_from_buffer();
+            }
+        }
+
+        fn populate_lines_from_buffer(&mut self) {
+            if self.buffer.is_empty() {
+                return;
+            }
+
+            let (splits,remainder) = split_iter_wrapper(self.buffer.as_slice(), self.eol);
+            match splits {
+                Option::None => { },
+                Option::Some(v) => {
+                    for (line,eol_flag) in v {
+                        let result = match std::str::from_utf8(line) {
+                            Ok(s) => Ok((s.to_string(),eol_flag)),
+                            Err(_) => Err(io::Error::new(io::ErrorKind::InvalidData, "stream does not contain valid utf8 data")),
+                        };
+                        self.stack.push_back(result);
+                    }
+                }
+            };
+            match remainder {
+                Option::None => {
+                    unsafe {
+                        self.buffer.set_len(0);
+                    }
+                }
+                Option::Some(left_over) => {
+                    if left_over.len() != self.buffer.len() {
+                        unsafe {
+                            std::ptr::copy::<u8>(left_over.as_ptr(), self.buffer.as_ptr() as *mut u8, left_over.len());
+                            self.buffer.set_len(left_over.len());
+                        };
+                    }
+                }
+            };
+
+        }
+
+        fn read_until_approx_eol(&mut self) {
+            if self.end {
+                return;
+            }
+
+            if self.eol.is_empty() {
+                unsafe { std::hint::unreachable_unchecked() };
+            } else if self.eol.len() == 1 {
+                match self.reader.read_until(self.eol[0], &mut self.buffer) {
+                    Ok(x) => {
+                        if x == 0 {
+                            self.end = true;
+                        }
+                    },
+                    Err(e) => {
+                        self.stack.push_back(Err(e));
+                    },
+                };
+            } else {
+                for b in self.eol.iter() {
+                    match self.reader.read_until(*b, &mut self.buffer) {
+                        Ok(x) => {
+                            if x == 0 {
+                                self.end = true;
+                            }
+                        },
+                        Err(e) => {
+                            self.stack.push_back(Err(e));
+                        }
+                    };
+                }
+            }
+        }
+    }
+
+    /// the lines ending with `needle`, and what's left after them
+    type Split<'a, T> = (Option<Vec<(&'a [T], bool)>>,Option<&'a [T]>);
+
+    fn split_iter_wrapper<'a, T: Eq+'static>(arg: &'a [T], needle: &'static [T]) -> Split<'a, T> {
+        let mut items = SplitIterator::new(arg, needle)
+            .collect::<Vec<_>>();
+        let (last,terminated_by_eol) = items.pop().unwrap();
+        if !terminated_by_eol {
+            if items.is_empty() {
+                (None,Some(last))
+            } else {
+                (Some(items),Some(last))
+            }
+        } else {
+            items.push((last, terminated_by_eol));
+            (Some(items),None)
+        }
+    }
+}
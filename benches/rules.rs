@@ -0,0 +1,76 @@
+//! Compares finding the first matching rule for every line of a large
+//! log with a `RegexSet` prefilter against running each rule's regex
+//! in turn. Run with `cargo bench --bench rules`.
+
+#[allow(dead_code)]
+#[path = "../src/rules.rs"]
+mod rules;
+
+use std::time::{Duration,Instant};
+
+use regex::{Regex,RegexSetBuilder};
+
+use rules::{Rules,SET_DFA_SIZE_LIMIT};
+
+const RULES: usize = 40;
+const LINES: usize = 200_000;
+const ROUNDS: usize = 3;
+
+/// A deterministic corpus where about 1 line in 50 matches a rule.
+fn corpus() -> String {
+    let mut seed = 0x2545_f491_4f6c_dd1du64;
+    let mut next = move || {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        seed
+    };
+    let mut text = String::with_capacity(LINES * 80);
+    for line in 0..LINES {
+        let r = next();
+        if r % 50 == 0 {
+            text.push_str(&format!("2022-03-{:02} svc{} event{}: user=u{} took={}ms\n", line % 28 + 1, r % 7, r % RULES as u64, r % 1000, r % 500));
+        } else {
+            text.push_str(&format!("2022-03-{:02} svc{} INFO request {} served in {}ms from cache {}\n", line % 28 + 1, r % 7, r, r % 900, r % 13));
+        }
+    }
+    text
+}
+
+fn patterns() -> Vec<String> {
+    (0..RULES).map(|i| format!(r#"event{}: user=(?P<user>\w+) took=(\d+)ms"#, i)).collect()
+}
+
+/// the fastest of a few rounds, with the number of lines which matched
+fn time<F: Fn(&str) -> bool>(text: &str, f: F) -> (Duration, usize) {
+    let mut best = Duration::MAX;
+    let mut matched = 0;
+    for _ in 0..ROUNDS {
+        let start = Instant::now();
+        matched = text.lines().filter(|line| f(line)).count();
+        best = best.min(start.elapsed());
+    }
+    (best, matched)
+}
+
+fn main() {
+    let text = corpus();
+    let patterns = patterns();
+    let regexes = patterns.iter().map(|p| Regex::new(p).unwrap()).collect::<Vec<_>>();
+    let set = RegexSetBuilder::new(&patterns).dfa_size_limit(SET_DFA_SIZE_LIMIT).build().unwrap();
+    let rules = Rules::new(set, regexes.clone());
+
+    let (each, each_matched) = time(&text, |line| {
+        regexes.iter().find_map(|regex| regex.captures(line)).is_some()
+    });
+    let (set, set_matched) = time(&text, |line| {
+        rules.first_match(line).and_then(|rule| rules.regex(rule).captures(line)).is_some()
+    });
+    assert_eq!(each_matched, set_matched);
+
+    let mb = text.len() as f64 / (1024.0 * 1024.0);
+    println!("{} rules, {} lines ({:.1} MiB), {} lines matched", RULES, LINES, mb, set_matched);
+    println!("every regex:       {:>10.2?} {:>8.1} MiB/s", each, mb / each.as_secs_f64());
+    println!("regex set first:   {:>10.2?} {:>8.1} MiB/s", set, mb / set.as_secs_f64());
+    println!("speedup:           {:>10.1}x", each.as_secs_f64() / set.as_secs_f64());
+}
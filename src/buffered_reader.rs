@@ -1,157 +1,106 @@
+use std::io::{self,Read,BufReader};
 
-use std::mem::replace;
-use std::collections::VecDeque;
-use std::io::{self,Read,BufReader,BufRead};
+use memchr::memmem::Finder;
+#[cfg(test)]
+use proptest::prelude::*;
 
+/// How much is read at once, a longer line grows the buffer.
+const INITIAL_CAPACITY: usize = 64 * 1024;
+
+/// Splits its input into lines ending with `eol`, which are lent out of
+/// an internal buffer one at a time rather than copied. The terminator is
+/// searched for in everything read so far, so one split between two
+/// reads is still found.
 pub struct BufferedReader<R: Read> {
-    buffer: Vec<u8>,
-    stack: VecDeque<io::Result<(String,bool)>>,
     reader: BufReader<R>,
+    buffer: Vec<u8>,
+    /// the next line starts here
+    start: usize,
+    /// the end of what's been read
+    end: usize,
     eol: &'static [u8],
-    end: bool,
+    finder: Finder<'static>,
+    eof: bool,
 }
 
-impl<R: Read> Iterator for BufferedReader<R> {
-    type Item=io::Result<(String,bool)>;
-    fn next(&mut self) -> Option<Self::Item> {
-        loop {
-            if self.end && self.stack.is_empty() && self.buffer.is_empty() {
-                // we are the EOF
-                // no more lines/errors
-                // no more buffered data
-                //
-                // iteration is over
-                return None;
-            }
+impl<R: Read> BufferedReader<R> {
 
-            match self.stack.pop_front() {
-                Option::None => {
-                    self.read_new_lines();
-                    continue;
-                }
-                Option::Some(x) => {
-                    return Some(x);
-                }
-            };
-        }
+    pub fn new(reader: BufReader<R>, eol: &'static [u8]) -> BufferedReader<R> {
+        BufferedReader::with_capacity(reader, eol, INITIAL_CAPACITY)
     }
-}
 
-impl<R: Read> BufferedReader<R> {
-
-    pub fn new(buffer: BufReader<R>, eol: &'static [u8]) -> BufferedReader<R> {
+    pub fn with_capacity(reader: BufReader<R>, eol: &'static [u8], capacity: usize) -> BufferedReader<R> {
+        assert!(!eol.is_empty());
         BufferedReader {
-            buffer: Vec::new(),
-            stack: VecDeque::new(),
-            reader: buffer,
-            eol: eol,
-            end: false
+            reader,
+            buffer: vec![0; capacity.max(1)],
+            start: 0,
+            end: 0,
+            eol,
+            finder: Finder::new(eol),
+            eof: false,
         }
     }
 
-    fn read_new_lines(&mut self) {
+    /// The next line, without its terminator, and if it had one. Only
+    /// the last line of the input can be without one.
+    pub fn next_line(&mut self) -> Option<io::Result<(&str,bool)>> {
+        // where the terminator hasn't been looked for yet
+        let mut searched = 0usize;
         loop {
-            if self.end && self.stack.is_empty() && self.buffer.is_empty() {
-                // nothing more to iterate
-                // iterator is over
-                return;
+            if let Some(pos) = self.finder.find(&self.buffer[self.start + searched..self.end]) {
+                let line = self.start..self.start + searched + pos;
+                self.start = line.end + self.eol.len();
+                return Some(as_str(&self.buffer[line]).map(|line| (line, true)));
+            }
+            if self.eof {
+                if self.start == self.end {
+                    return None;
+                }
+                let line = self.start..self.end;
+                self.start = self.end;
+                return Some(as_str(&self.buffer[line]).map(|line| (line, false)));
             }
-            if !self.stack.is_empty() {
-                // there are lines to return
-                return;
+            // the end may be the start of a terminator
+            searched = (self.end - self.start).saturating_sub(self.eol.len() - 1);
+            if let Err(e) = self.fill() {
+                return Some(Err(e));
             }
-            self.read_until_approx_eol();    
-            self.populate_lines_from_buffer();
         }
     }
 
-    fn populate_lines_from_buffer(&mut self) {
-        if self.buffer.is_empty() {
-            return;
+    /// Moves the unfinished line to the front of the buffer, growing
+    /// it when that's all it holds, and reads after it.
+    fn fill(&mut self) -> io::Result<()> {
+        if self.start > 0 {
+            self.buffer.copy_within(self.start..self.end, 0);
+            self.end -= self.start;
+            self.start = 0;
         }
-
-        let (splits,remainder) = split_iter_wrapper(self.buffer.as_slice(), self.eol);
-        match splits {
-            Option::None => { },
-            Option::Some(v) => {
-                for (line,eol_flag) in v {
-                    let result = match std::str::from_utf8(line) {
-                        Ok(s) => Ok((s.to_string(),eol_flag)),
-                        Err(_) => Err(io::Error::new(io::ErrorKind::InvalidData, "stream does not contain valid utf8 data")),
-                    };
-                    self.stack.push_back(result);
-                }
-            }
-        };
-        match remainder {
-            Option::None => {
-                unsafe {
-                    self.buffer.set_len(0);
+        if self.end == self.buffer.len() {
+            let len = self.buffer.len() * 2;
+            self.buffer.resize(len, 0);
+        }
+        loop {
+            match self.reader.read(&mut self.buffer[self.end..]) {
+                Ok(0) => {
+                    self.eof = true;
+                    return Ok(());
                 }
-            }
-            Option::Some(left_over) => {
-                if left_over.len() == self.buffer.len() {
-                    return;
-                } else {
-                    unsafe {
-                        std::intrinsics::copy::<u8>(left_over.as_ptr(), self.buffer.as_ptr() as *mut u8, left_over.len());
-                        self.buffer.set_len(left_over.len());
-                    };
+                Ok(n) => {
+                    self.end += n;
+                    return Ok(());
                 }
-            }
-        };
-        
-    }
-
-    fn read_until_approx_eol(&mut self) {
-        if self.end {
-            return;
-        }
-
-        if self.eol.is_empty() {
-            unsafe { std::hint::unreachable_unchecked() };
-        } if self.eol.len() == 1 {
-            match self.reader.read_until(self.eol[0], &mut self.buffer) {
-                Ok(x) => {
-                    if x == 0 {
-                        self.end = true;
-                    }
-                },
-                Err(e) => {
-                    self.stack.push_back(Err(e));
-                },
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
             };
-        } else {
-            for b in self.eol.iter() {
-                match self.reader.read_until(*b, &mut self.buffer) {
-                    Ok(x) => {
-                        if x == 0 {
-                            self.end = true;
-                        }
-                    },
-                    Err(e) => {
-                        self.stack.push_back(Err(e));
-                    }
-                };
-            }
         }
     }
 }
 
-fn split_iter_wrapper<'a, T: Eq+'static>(arg: &'a [T], needle: &'static [T]) -> (Option<Vec<(&'a [T], bool)>>,Option<&'a [T]>) {
-    let mut items = SplitIterator::new(arg, needle)
-        .collect::<Vec<_>>();
-    let (last,terminated_by_eol) = items.pop().unwrap();
-    if !terminated_by_eol {
-        if items.is_empty() {
-            (None,Some(last))
-        } else {
-            (Some(items),Some(last))
-        }
-    } else {
-        items.push((last, terminated_by_eol));
-        (Some(items),None)
-    }
+fn as_str(line: &[u8]) -> io::Result<&str> {
+    std::str::from_utf8(line)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "stream does not contain valid utf8 data"))
 }
 
 pub struct SplitIterator<'a,T: Eq +'static> {
@@ -172,7 +121,7 @@ impl<'a,T: Eq +'static> Iterator for SplitIterator<'a,T> {
         }
 
         // move haystack to the stack for easier manipluation
-        let haystack = replace(&mut self.hayheap, &[]);
+        let haystack = std::mem::take(&mut self.hayheap);
       
         if haystack.len() <= self.needle.len() {
             if haystack == self.needle {
@@ -186,22 +135,22 @@ impl<'a,T: Eq +'static> Iterator for SplitIterator<'a,T> {
             .position(|window| window == self.needle)
         {
             Option::None => {
-                return Some((haystack, false))
+                Some((haystack, false))
             }
             Option::Some(pos) => {
                 let (before, after) = haystack.split_at(pos);
-                let remainder = after.strip_prefix(self.needle).unwrap_or_else(|| after);
+                let remainder = after.strip_prefix(self.needle).unwrap_or(after);
                 self.hayheap = remainder;
-                return Some((before,true));
+                Some((before,true))
             }
-        };
+        }
     }
 }
 
 #[test]
 fn test_split_iterator() {
-    const SPLIT: &'static [u8] = &[0x0A];
-    const X: &'static str = r#"
+    const SPLIT: &[u8] = &[0x0A];
+    const X: &str = r#"
 hello 
 world
 this
@@ -226,17 +175,80 @@ g
     assert_eq!(&iter[8].0, b"g");
     assert_eq!(iter[9].0.len(), 1);
     assert_eq!(&iter[9].0, b" ");
-    assert_eq!(iter[9].1, false);
+    assert!(!iter[9].1);
 }
 
 #[test]
 fn test_split_iterator_2() {
-    const SPLIT: &'static [u8] = b"ee";
-    const X: &'static str = "helloeeworldee";
+    const SPLIT: &[u8] = b"ee";
+    const X: &str = "helloeeworldee";
     let iter = SplitIterator::new(X.as_bytes(), SPLIT).collect::<Vec<_>>();
     assert_eq!(iter.len(), 2);
     assert_eq!(iter[0].0, b"hello");
-    assert_eq!(iter[0].1, true);
+    assert!(iter[0].1);
     assert_eq!(iter[1].0, b"world");
-    assert_eq!(iter[1].1, true);
+    assert!(iter[1].1);
+}
+
+#[test]
+fn test_buffered_reader() {
+    const X: &str = "one\r\ntwo\rstill two\n\r\n\r\nlast";
+    for capacity in 1..12 {
+        let mut reader = BufferedReader::with_capacity(BufReader::new(X.as_bytes()), b"\r\n", capacity);
+        let mut lines = Vec::new();
+        while let Some(res) = reader.next_line() {
+            let (line, eol) = res.unwrap();
+            lines.push((line.to_string(), eol));
+        }
+        let expected = [("one", true), ("two\rstill two\n", true), ("", true), ("last", false)];
+        assert_eq!(lines, expected.iter().map(|&(l, e)| (l.to_string(), e)).collect::<Vec<_>>(), "{}", capacity);
+    }
+
+    let mut reader = BufferedReader::new(BufReader::new(&b"a\n\xff\nb\n"[..]), b"\n");
+    assert_eq!(reader.next_line().unwrap().unwrap(), ("a", true));
+    assert_eq!(reader.next_line().unwrap().unwrap_err().kind(), io::ErrorKind::InvalidData);
+    assert_eq!(reader.next_line().unwrap().unwrap(), ("b", true));
+    assert!(reader.next_line().is_none());
+}
+
+/// Hands out its input a few bytes at a time, so
+/// terminators end up split between reads.
+#[cfg(test)]
+pub struct Trickle<'a> {
+    pub data: &'a [u8],
+    pub sizes: Vec<usize>,
+    pub reads: usize,
+}
+#[cfg(test)]
+impl<'a> Read for Trickle<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.sizes[self.reads % self.sizes.len()].min(buf.len()).min(self.data.len());
+        buf[..n].copy_from_slice(&self.data[..n]);
+        self.data = &self.data[n..];
+        self.reads += 1;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+proptest! {
+    #[test]
+    fn test_lines_match_split_iterator(
+        data in prop::collection::vec(prop::sample::select(vec![b'a', b'b', b'\r', b'\n']), 0..200),
+        eol in prop::sample::select(vec![&b"\n"[..], b"\r", b"\r\n", b"\n\r", b"aa", b"aba", b"\r\n\r\n"]),
+        capacity in 1..16usize,
+        sizes in prop::collection::vec(1..8usize, 1..8),
+    ) {
+        let trickle = Trickle { data: &data, sizes, reads: 0 };
+        let mut reader = BufferedReader::with_capacity(BufReader::with_capacity(1, trickle), eol, capacity);
+        let mut lines = Vec::new();
+        while let Some(res) = reader.next_line() {
+            let (line, eol) = res.unwrap();
+            lines.push((line.as_bytes().to_vec(), eol));
+        }
+        let expected = SplitIterator::new(&data, eol)
+            .map(|(line, eol)| (line.to_vec(), eol))
+            .collect::<Vec<_>>();
+        prop_assert_eq!(lines, expected);
+    }
 }
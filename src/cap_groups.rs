@@ -1,12 +1,119 @@
 
 use std::io::{self,Write};
+use std::borrow::Cow;
+use std::collections::HashSet;
 
 use regex::{Regex,Captures};
 use lazy_static::lazy_static;
 
+use crate::{
+    case::Case,
+    delimited::Quoting,
+    expr::{Calculation,CalcError},
+    format_spec::FormatSpec,
+    mapping::Mapping,
+    matcher::{self,Matcher,Match},
+};
+
+const CAP_GROUP_PATTERN: &str = r#"%(?P<escapegroup>%)?((<((?P<labelledgroup>[_A-Za-z][_A-Za-z0-9.\[\]]*)|(?P<multidigit>[0-9]+))(:(?P<map>map))?(:(?P<case>upper|lower|title|snake|camel))?(:(?P<spec>(.?[<>^])?[0-9]*(\.[0-9]+)?))?>)|(\[(?P<field>[0-9]+)\])|(?P<entire>&)|(?P<before>`)|(?P<after>')|(\{(?P<variable>line|match|count|offset|file|path|env:[A-Za-z_][A-Za-z0-9_]*)\})|(?P<singledigit>[0-9]))"#;
+
 lazy_static! {
-    static ref CAP_GROUP: Regex = Regex::new(r#"%(?P<escapegroup>%)?((<((?P<labelledgroup>[a-z][a-zA-Z0-9]+)|(?P<multidigit>[0-9]{2,}))>)|(?P<singledigit>[0-9]))"#).unwrap();
-    static ref SPECIAL: Regex = Regex::new(r#"((?P<tab>\x5Ct)|(?P<newline>\x5Cn)|(?P<carriagereturn>\x5Cr)|(?P<verticaltab>\x5Cv)|(?P<backslash>\x5C\x5C)|(?P<unicode>\x5C(u|x|U)\{(?P<unicodevalue>[a-f0-9A-F]{1,8})\}))"#).unwrap();
+    static ref CAP_GROUP: Regex = Regex::new(CAP_GROUP_PATTERN).unwrap();
+    /// `CAP_GROUP` only where the parser is, searching on would be quadratic
+    static ref CAP_GROUP_START: Regex = Regex::new(&format!("^(?:{})", CAP_GROUP_PATTERN)).unwrap();
+    static ref BRANCH: Regex = Regex::new(r#"^%<((?P<labelledgroup>[_A-Za-z][_A-Za-z0-9.\[\]]*)|(?P<multidigit>[0-9]+))(?P<kind>\?|:-)"#).unwrap();
+    static ref SPECIAL: Regex = Regex::new(r#"((?P<unicode>\x5C(u|x|U)\{(?P<unicodevalue>[a-f0-9A-F]{1,8})\})|(\x5CN\{(?P<unicodename>[A-Za-z0-9 \-]+)\})|(\x5Cx(?P<hexbyte>[a-f0-9A-F]{2}))|(\x5C(?P<octal>[0-3][0-7]{2}))|(?P<nul>\x5C0)|(?P<bell>\x5Ca)|(?P<escape>\x5Ce)|(?P<formfeed>\x5Cf)|(?P<tab>\x5Ct)|(?P<newline>\x5Cn)|(?P<carriagereturn>\x5Cr)|(?P<verticaltab>\x5Cv)|(?P<backslash>\x5C\x5C)|(?P<casespan>\x5C[UL])|(?P<caseend>\x5CE)|(?P<casenext>\x5C[ul]))"#).unwrap();
+}
+
+/// Everything a template may refer to besides the capture groups.
+#[derive(Clone,Copy,Default,Debug)]
+pub struct Context<'c> {
+    /// fields of the current row in field mode
+    pub fields: &'c [Cow<'c,str>],
+    /// write the capture as is when a calculation finds it isn't a number
+    pub pass_non_numeric: bool,
+    /// the input file as given on the command line, `-` for stdin
+    pub path: &'c str,
+    pub position: Position,
+    pub around: Around<'c>,
+    /// the `--map` file for `%<N:map>`
+    pub map: Option<&'c Mapping>,
+    /// a key missing from `map` is an error, rather than written as is
+    pub map_strict: bool,
+}
+
+/// The text either side of the current match, within its line in
+/// line-by-line mode or up to the neighbouring matches in continuous mode.
+#[derive(Clone,Copy,Default,PartialEq,Eq,Debug)]
+pub struct Around<'a> {
+    /// `` %` `` from the end of the previous match or start of the line
+    pub before: &'a str,
+    /// `%'` up to the start of the next match or end of the line
+    pub after: &'a str,
+}
+
+/// Where the current match is within the input.
+#[derive(Clone,Copy,Default,PartialEq,Eq,Debug)]
+pub struct Position {
+    /// line the match starts on, starting at `1`
+    pub line: usize,
+    /// the match's index within its line, starting at `1`
+    pub match_index: usize,
+    /// the match's index within the input, starting at `1`
+    pub count: usize,
+    /// byte offset of the start of the match
+    pub offset: usize,
+}
+
+/// `%{line}` `%{env:USER}` values which don't come from the match itself.
+#[derive(Clone,Copy,PartialEq,Eq,PartialOrd,Ord,Hash,Debug)]
+pub enum Variable<'a> {
+    Line,
+    Match,
+    Count,
+    Offset,
+    /// the file name without its directory
+    File,
+    Path,
+    Env(&'a str),
+}
+impl<'a> Variable<'a> {
+
+    pub fn from_name(name: &'a str) -> Option<Variable<'a>> {
+        match name {
+            "line" => Some(Variable::Line),
+            "match" => Some(Variable::Match),
+            "count" => Some(Variable::Count),
+            "offset" => Some(Variable::Offset),
+            "file" => Some(Variable::File),
+            "path" => Some(Variable::Path),
+            _ => name.strip_prefix("env:").map(Variable::Env),
+        }
+    }
+
+    fn write(&self, ctx: &Context<'_>, buffer: &mut String) {
+        use std::fmt::Write;
+        let _ = match self {
+            Variable::Line => write!(buffer, "{}", ctx.position.line),
+            Variable::Match => write!(buffer, "{}", ctx.position.match_index),
+            Variable::Count => write!(buffer, "{}", ctx.position.count),
+            Variable::Offset => write!(buffer, "{}", ctx.position.offset),
+            Variable::File => {
+                let file = std::path::Path::new(ctx.path)
+                    .file_name()
+                    .map(|name| name.to_string_lossy())
+                    .unwrap_or(Cow::Borrowed(ctx.path));
+                buffer.write_str(&file)
+            }
+            Variable::Path => buffer.write_str(ctx.path),
+            Variable::Env(name) => {
+                if let Some(value) = std::env::var_os(name) {
+                    buffer.push_str(&value.to_string_lossy());
+                }
+                Ok(())
+            }
+        };
+    }
 }
 
 #[derive(Clone,PartialEq,Eq,PartialOrd,Ord,Hash,Debug)]
@@ -17,130 +124,282 @@ pub enum CapGroup<'a> {
     Escape(&'a str),
     CopyFromInput(&'a str),
     SingleChar(char),
+    Quoted(Box<CapGroup<'a>>, Quoting),
+    /// `%[N]` a field of the current row, starting at `1`
+    Field(usize),
+    /// `%<N:map>` a capture group replaced by its value in the `--map` file
+    Mapped(Box<CapGroup<'a>>),
+    /// `%<N:upper>` a capture group converted to another case
+    Converted(Box<CapGroup<'a>>, Case),
+    /// `\U` or `\L`, everything up to `\E` is converted
+    CaseSpan(Case),
+    /// `\E`
+    CaseEnd,
+    /// `\u` or `\l`, the next character is converted
+    CaseNext(Case),
+    /// `%<N:>10>` `%<N:*^8.3>` a capture group padded or truncated
+    Formatted(Box<CapGroup<'a>>, FormatSpec),
+    /// `%<N:-default>` the group, or `default` when it is unset or empty
+    Default(Box<CapGroup<'a>>, Vec<CapGroup<'a>>),
+    /// `%<N?set:unset>` one of two templates depending on the group
+    Conditional(Box<CapGroup<'a>>, Vec<CapGroup<'a>>, Vec<CapGroup<'a>>),
+    /// `%<N+1>` `%<N*1000:08>` `%<N:#x>` arithmetic on a captured number
+    Calculated(Box<Calculation<'a>>),
+    /// `%{line}` `%{file}` `%{env:NAME}` a built in variable
+    Variable(Variable<'a>),
+    /// `` %` `` the text before the match
+    Before,
+    /// `%'` the text after the match
+    After,
 }
 impl<'a> CapGroup<'a> {
 
-    pub fn output(groups: &[CapGroup<'a>], caps: &Captures<'_>, buffer: &mut String) {
+    /// Writing to a `String` cannot fail, but a calculation can.
+    pub fn output(groups: &[CapGroup<'a>], caps: &matcher::Captures<'_>, ctx: &Context<'_>, buffer: &mut String) -> io::Result<()> {
+        let mut out = Cased::new(buffer);
+        Self::render(groups, caps, ctx, &mut out)
+    }
+
+    pub fn steam_output<W: Write + ?Sized>(groups: &[CapGroup<'a>], caps: &matcher::Captures<'_>, ctx: &Context<'_>, output: &mut W) -> io::Result<()> {
+        let mut sink = IoSink(output);
+        let mut out = Cased::new(&mut sink);
+        Self::render(groups, caps, ctx, &mut out)
+    }
+
+    fn render<S: Sink>(groups: &[CapGroup<'a>], caps: &matcher::Captures<'_>, ctx: &Context<'_>, out: &mut Cased<'_,S>) -> io::Result<()> {
         for g in groups.iter() {
             match g {
                 &CapGroup::MultiDigit(ref x) |
                 &CapGroup::SingleDigit(ref x) => {
                     match caps.get(*x) {
                         Option::None => { }
-                        Option::Some(ref m) => buffer.push_str(m.as_str()),
+                        Option::Some(ref m) => out.push_str(m.as_str())?,
                     };
                 }
-                &CapGroup::Labelled(ref label) => {
+                &CapGroup::Labelled(label) => {
                     match caps.name(label) {
                         Option::None => { },
-                        Option::Some(ref m) => buffer.push_str(m.as_str()),
+                        Option::Some(ref m) => out.push_str(m.as_str())?,
                     };
                 }
                 &CapGroup::Escape(x) |
-                &CapGroup::CopyFromInput(x) => buffer.push_str(x),
-                &CapGroup::SingleChar(c) => buffer.push(c),
-            };
-        }
-    }
-
-    pub fn steam_output<W: Write>(groups: &[CapGroup<'a>], caps: &Captures<'_>, output: &mut W) -> io::Result<()> {
-        for g in groups.iter() {
-            match g {
-                &CapGroup::MultiDigit(ref x) |
-                &CapGroup::SingleDigit(ref x) => {
-                    match caps.get(*x) {
-                        Option::None => { }
-                        Option::Some(ref m) => {
-                            output.write_all(m.as_str().as_bytes())?;
-                        }
-                    };
+                &CapGroup::CopyFromInput(x) => out.push_str(x)?,
+                &CapGroup::SingleChar(c) => {
+                    let mut b = [0u8; 4];
+                    out.push_str(c.encode_utf8(&mut b))?;
                 }
-                &CapGroup::Labelled(ref label) => {
-                    match caps.name(label) {
+                CapGroup::Quoted(inner, quoting) => {
+                    let mut s = String::new();
+                    CapGroup::output(std::slice::from_ref(inner.as_ref()), caps, ctx, &mut s)?;
+                    let mut quoted = String::with_capacity(s.len() + 2);
+                    quoting.quote(&s, &mut quoted);
+                    out.push_str(&quoted)?;
+                }
+                &CapGroup::Field(x) => {
+                    match x.checked_sub(1).and_then(|x| ctx.fields.get(x)) {
                         Option::None => { },
-                        Option::Some(ref m) => {
-                            output.write_all(m.as_str().as_bytes())?;
+                        Option::Some(field) => out.push_str(field)?,
+                    };
+                }
+                CapGroup::Mapped(inner) => {
+                    let mut s = String::new();
+                    CapGroup::output(std::slice::from_ref(inner.as_ref()), caps, ctx, &mut s)?;
+                    match ctx.map.and_then(|map| map.get(&s)) {
+                        Option::Some(value) => out.push_str(value)?,
+                        Option::None if ctx.map_strict => {
+                            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{:?} isn't a key in the '--map' file", s)));
                         }
+                        Option::None => out.push_str(&s)?,
                     };
                 }
-                &CapGroup::Escape(x) |
-                &CapGroup::CopyFromInput(x) => {
-                    output.write_all(x.as_bytes())?;
+                CapGroup::Converted(inner, case) => {
+                    let mut s = String::new();
+                    CapGroup::output(std::slice::from_ref(inner.as_ref()), caps, ctx, &mut s)?;
+                    let mut converted = String::with_capacity(s.len());
+                    case.convert(&s, &mut converted);
+                    out.push_str(&converted)?;
                 }
-                &CapGroup::SingleChar(c) => {
+                CapGroup::Formatted(inner, spec) => {
+                    let mut s = String::new();
+                    CapGroup::output(std::slice::from_ref(inner.as_ref()), caps, ctx, &mut s)?;
+                    let mut formatted = String::with_capacity(spec.width.max(s.len()));
+                    spec.apply(&s, &mut formatted);
+                    out.push_str(&formatted)?;
+                }
+                &CapGroup::CaseSpan(case) => out.span = Some(case),
+                &CapGroup::CaseEnd => out.span = None,
+                &CapGroup::CaseNext(case) => out.next = Some(case),
+                CapGroup::Default(reference, default) => {
+                    match reference.capture(caps).filter(|m| !m.as_str().is_empty()) {
+                        Option::None => Self::render(default, caps, ctx, out)?,
+                        Option::Some(m) => out.push_str(m.as_str())?,
+                    };
+                }
+                CapGroup::Conditional(reference, set, unset) => {
+                    let is_set = reference.capture(caps).filter(|m| !m.as_str().is_empty()).is_some();
+                    Self::render(if is_set { set } else { unset }, caps, ctx, out)?;
+                }
+                &CapGroup::Before => out.push_str(ctx.around.before)?,
+                &CapGroup::After => out.push_str(ctx.around.after)?,
+                CapGroup::Variable(var) => {
                     let mut s = String::new();
-                    s.push(c);
-                    output.write_all(s.as_bytes())?;
+                    var.write(ctx, &mut s);
+                    out.push_str(&s)?;
+                }
+                CapGroup::Calculated(calc) => {
+                    let mut s = String::new();
+                    match calc.write(caps, &mut s) {
+                        Ok(()) => out.push_str(&s)?,
+                        Err(CalcError::NotANumber(_)) if ctx.pass_non_numeric => out.push_str(calc.fallback(caps))?,
+                        Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e.to_string())),
+                    };
                 }
             };
         }
         Ok(())
     }
 
+    /// Parses a format string into the groups to output, anything that
+    /// doesn't parse as a capture group or escape is copied literally.
     pub fn build_groups(arg: &'a str) -> Vec<CapGroup<'a>> {
-        let mut todo_list = Vec::with_capacity(1);
-        let mut old_start = 0usize;
-        for cap in CAP_GROUP.captures_iter(arg) {
-            let (start,end) = match cap.get(0) {
-                Option::None => unsafe { std::hint::unreachable_unchecked() },
-                Option::Some(ref m) => (m.start(), m.end()),
+        let mut parser = Parser { arg, pos: 0, depth: 0, failed: HashSet::new(), too_deep: false };
+        parser.template(&[]).0
+    }
+
+    /// Checks every capture group referenced by `groups` exists in `regex`,
+    /// returning a message for each one which doesn't.
+    pub fn check_references(groups: &[CapGroup<'a>], regex: &dyn Matcher) -> Vec<String> {
+        let mut problems = Vec::new();
+        Self::visit(groups, &mut |g| {
+            match g {
+                &CapGroup::MultiDigit(x) |
+                &CapGroup::SingleDigit(x) if x >= regex.captures_len() => {
+                    problems.push(format!("the format string references group '{}' but the regex only has groups 0 to {}", x, regex.captures_len() - 1));
+                }
+                &CapGroup::Labelled(label) if regex.group_index(label).is_none() => {
+                    let closest = regex.group_names()
+                        .into_iter()
+                        .flatten()
+                        .map(|name| (edit_distance(label, name), name))
+                        .min();
+                    problems.push(match closest {
+                        Option::Some((distance, name)) if distance <= 1 + label.chars().count() / 2 => {
+                            format!("the format string references group '{}' but the regex has no group by that name, did you mean '{}'?", label, name)
+                        }
+                        _ => format!("the format string references group '{}' but the regex has no group by that name", label),
+                    });
+                }
+                _ => { }
+            };
+        });
+        problems
+    }
+
+    /// Whether any group is looked up in a `--map` file.
+    pub fn uses_map(groups: &[CapGroup<'a>]) -> bool {
+        let mut found = false;
+        Self::visit(groups, &mut |g| found |= matches!(g, &CapGroup::Mapped(_)));
+        found
+    }
+
+    /// The first `\xHH` or `\NNN` escape in `arg` above `0x7F`, those are
+    /// bytes rather than characters and the output is always UTF-8.
+    pub fn find_byte_escape(arg: &str) -> Option<&str> {
+        SPECIAL.captures_iter(arg)
+            .find(|cap| {
+                let value = cap.name("hexbyte").map(|m| u32::from_str_radix(m.as_str(), 16))
+                    .or_else(|| cap.name("octal").map(|m| u32::from_str_radix(m.as_str(), 8)));
+                matches!(value, Some(Ok(x)) if x > 0x7F)
+            })
+            .and_then(|cap| cap.get(0))
+            .map(|m| m.as_str())
+    }
+
+    /// Whether any group is `%{count}`, which needs every match before it.
+    pub fn uses_count(groups: &[CapGroup<'a>]) -> bool {
+        let mut found = false;
+        Self::visit(groups, &mut |g| found |= matches!(g, &CapGroup::Variable(Variable::Count)));
+        found
+    }
+
+    /// Calls `f` with every group in `groups` and every group nested within them.
+    fn visit<F: FnMut(&CapGroup<'a>)>(groups: &[CapGroup<'a>], f: &mut F) {
+        for g in groups.iter() {
+            f(g);
+            match g {
+                CapGroup::Quoted(inner, _) |
+                CapGroup::Converted(inner, _) |
+                CapGroup::Formatted(inner, _) |
+                CapGroup::Mapped(inner) => Self::visit(std::slice::from_ref(inner.as_ref()), f),
+                CapGroup::Default(reference, default) => {
+                    Self::visit(std::slice::from_ref(reference.as_ref()), f);
+                    Self::visit(default, f);
+                }
+                CapGroup::Conditional(reference, set, unset) => {
+                    Self::visit(std::slice::from_ref(reference.as_ref()), f);
+                    Self::visit(set, f);
+                    Self::visit(unset, f);
+                }
+                CapGroup::Calculated(calc) => {
+                    for group in calc.groups() {
+                        f(group);
+                    }
+                }
+                _ => { }
             };
-            if start != old_start {
-                Self::build_spec_groups(slice_str(arg,old_start,start-1), &mut todo_list);
-            }
-            old_start = end;
-            todo_list.push(CapGroup::build(cap));
         }
-        if (old_start+1) <= arg.len() {
-            Self::build_spec_groups(slice_str(arg, old_start, arg.len()-1), &mut todo_list);
+    }
+
+    /// The capture group a `MultiDigit`, `SingleDigit` or `Labelled` refers to.
+    pub(crate) fn capture<'t>(&self, caps: &matcher::Captures<'t>) -> Option<Match<'t>> {
+        match self {
+            &CapGroup::MultiDigit(x) |
+            &CapGroup::SingleDigit(x) => caps.get(x),
+            &CapGroup::Labelled(label) => caps.name(label),
+            _ => None,
         }
-        todo_list
     }
 
+    /// A group numbered past `usize::MAX` can't refer to
+    /// anything, its text is copied as is.
     fn build(cap: Captures<'a>) -> CapGroup<'a> {
-        let entire = match cap.get(0) {
-            Option::None => unsafe { std::hint::unreachable_unchecked() },
-            Option::Some(m) => m.as_str(),
-        };
+        let entire = cap.get(0).map_or("", |m| m.as_str());
         if cap.name("escapegroup").is_some() {
-            // remove first symbol in a fairly unsafe fashion.
-            // we know the first character is an ASCII `%` so
-            // we're just removing 1 byte and we know the match
-            // is at least 3 bytes long, we this is safe, even
-            // if rust doesn't think it is.
-            //
-            // going to do this as unsafely as possible as
-            // a test to see when the MIRI jerks decides to
-            // stop letting rust be cool.
-            let x = unsafe {
-                let rest = match entire.as_bytes().split_first() {
-                    Option::Some((_, rest)) => rest,
-                    Option::None => std::hint::unreachable_unchecked(),
-                };
-                std::str::from_utf8_unchecked(rest)
-            };
-            CapGroup::Escape(x)
+            // the first `%` is dropped
+            CapGroup::Escape(&entire[1..])
+        } else if cap.name("entire").is_some() {
+            CapGroup::SingleDigit(0)
+        } else if cap.name("before").is_some() {
+            CapGroup::Before
+        } else if cap.name("after").is_some() {
+            CapGroup::After
+        } else if let Some(var) = cap.name("variable").and_then(|m| Variable::from_name(m.as_str())) {
+            CapGroup::Variable(var)
         } else {
-            match cap.name("labelledgroup")
-                .map(|m| CapGroup::Labelled(m.as_str()))
-                .into_iter()
-                .chain( cap.name("multidigit")
-                        .into_iter()
-                        .filter_map(|m| usize::from_str_radix(m.as_str(), 10).ok())
-                        .map(|d| CapGroup::MultiDigit(d)))
-                .chain( cap.name("singledigit")
-                        .into_iter()
-                        .filter_map(|m| usize::from_str_radix(m.as_str(), 10).ok())
-                        .map(|d| CapGroup::SingleDigit(d)))
-                .next()
-            {
-                Option::None => unsafe { std::hint::unreachable_unchecked() },
-                Option::Some(out) => out,
+            let number = |name: &str| cap.name(name).map(|m| m.as_str().parse::<usize>().ok());
+            let group = match (cap.name("labelledgroup"), number("field"), number("multidigit"), number("singledigit")) {
+                (Option::Some(m), _, _, _) => CapGroup::Labelled(m.as_str()),
+                (_, Option::Some(Option::Some(x)), _, _) => CapGroup::Field(x),
+                (_, _, Option::Some(Option::Some(x)), _) => CapGroup::MultiDigit(x),
+                (_, _, _, Option::Some(Option::Some(x))) => CapGroup::SingleDigit(x),
+                _ => return CapGroup::CopyFromInput(entire),
+            };
+            let group = match cap.name("map") {
+                Option::None => group,
+                Option::Some(_) => CapGroup::Mapped(Box::new(group)),
+            };
+            let group = match cap.name("case").and_then(|m| Case::from_name(m.as_str())) {
+                Option::None => group,
+                Option::Some(case) => CapGroup::Converted(Box::new(group), case),
+            };
+            match cap.name("spec").and_then(|m| FormatSpec::parse(m.as_str())) {
+                Option::None => group,
+                Option::Some(spec) => CapGroup::Formatted(Box::new(group), spec),
             }
         }
     }
 
-    fn build_spec_groups(arg: &'a str, todo_list: &mut Vec<CapGroup<'a>>) {
+    pub(crate) fn build_spec_groups(arg: &'a str, todo_list: &mut Vec<CapGroup<'a>>) {
         let mut old_start = 0usize;
         for cap in SPECIAL.captures_iter(arg) {
             let (start,end) = match cap.get(0) {
@@ -160,30 +419,297 @@ impl<'a> CapGroup<'a> {
         }
     }
 
+    /// An escape which doesn't name a character, such as an unknown
+    /// `\N{..}` or a surrogate `\u{D800}`, is copied as is.
     fn build_spec(cap: Captures<'a>) -> Option<CapGroup<'a>> {
         Option::None
             .into_iter()
+            .chain(cap.name("nul").is_some()
+                   .then_some(CapGroup::SingleChar('\0')))
+            .chain(cap.name("bell").is_some()
+                   .then_some(CapGroup::SingleChar('\u{07}')))
+            .chain(cap.name("escape").is_some()
+                   .then_some(CapGroup::SingleChar('\u{1B}')))
+            .chain(cap.name("formfeed").is_some()
+                   .then_some(CapGroup::SingleChar('\u{0C}')))
             .chain(cap.name("tab").is_some()
-                   .then(|| CapGroup::SingleChar('\t')))
+                   .then_some(CapGroup::SingleChar('\t')))
             .chain(cap.name("newline").is_some()
-                   .then(|| CapGroup::SingleChar('\n')))
+                   .then_some(CapGroup::SingleChar('\n')))
             .chain(cap.name("carriagereturn").is_some()
-                   .then(|| CapGroup::SingleChar('\r')))
+                   .then_some(CapGroup::SingleChar('\r')))
             .chain(cap.name("verticaltab").is_some()
-                   .then(|| CapGroup::SingleChar('\u{0B}')))
+                   .then_some(CapGroup::SingleChar('\u{0B}')))
             .chain(cap.name("backslash").is_some()
-                   .then(|| CapGroup::SingleChar('\u{0B}')))
+                   .then_some(CapGroup::SingleChar('\\')))
+            .chain(cap.name("casespan")
+                   .map(|m| CapGroup::CaseSpan(if m.as_str() == "\\U" { Case::Upper } else { Case::Lower })))
+            .chain(cap.name("caseend")
+                   .map(|_| CapGroup::CaseEnd))
+            .chain(cap.name("casenext")
+                   .map(|m| CapGroup::CaseNext(if m.as_str() == "\\u" { Case::Upper } else { Case::Lower })))
             .chain(cap.name("unicodevalue")
                    .into_iter()
                    .filter_map(|m| u32::from_str_radix(m.as_str(),16).ok())
-                   .filter_map(|u| char::from_u32(u))
-                   .map(|c| CapGroup::SingleChar(c))
+                   .filter_map(char::from_u32)
+                   .map(CapGroup::SingleChar)
+                   .next())
+            // a byte isn't a character, see `find_byte_escape`
+            .chain(cap.name("hexbyte")
+                   .into_iter()
+                   .filter_map(|m| u32::from_str_radix(m.as_str(),16).ok())
+                   .filter(|&u| u <= 0x7F)
+                   .filter_map(char::from_u32)
+                   .map(CapGroup::SingleChar)
                    .next())
+            .chain(cap.name("octal")
+                   .into_iter()
+                   .filter_map(|m| u32::from_str_radix(m.as_str(),8).ok())
+                   .filter(|&u| u <= 0x7F)
+                   .filter_map(char::from_u32)
+                   .map(CapGroup::SingleChar)
+                   .next())
+            .chain(cap.name("unicodename")
+                   .and_then(|m| unicode_names2::character(m.as_str()))
+                   .map(CapGroup::SingleChar))
+            .chain(cap.get(0)
+                   .map(|m| CapGroup::CopyFromInput(m.as_str())))
             .next()
     }
 }
 
-fn slice_str<'a>(arg: &'a str, start: usize, end: usize) -> &'a str {
+/// A branch with others nested deeper than this within it is left as
+/// literal text, parsing them would run out of stack.
+const MAX_NESTING: usize = 64;
+
+/// Splits a format string into literal text and groups. `%<N:-..>` and
+/// `%<N?..:..>` hold templates of their own, so this walks the string by
+/// hand and leaves the simple groups and escapes to `CAP_GROUP` and `SPECIAL`,
+/// calculations are parsed by `Calculation`.
+struct Parser<'a> {
+    arg: &'a str,
+    pos: usize,
+    /// the number of branches the parser is within
+    depth: usize,
+    /// where a branch turned out to be unterminated, it is the same
+    /// whichever branch it's within so it's only parsed once
+    failed: HashSet<usize>,
+    /// set on reaching `MAX_NESTING`, every branch the parser is within fails
+    too_deep: bool,
+}
+impl<'a> Parser<'a> {
+
+    /// Parses up to the end of the input or to the first character of `stop`
+    /// that isn't part of a nested group, which is consumed and returned.
+    /// Within a branch a `stop` character is written literally as `\:` `\>`.
+    fn template(&mut self, stop: &[char]) -> (Vec<CapGroup<'a>>,Option<char>) {
+        let arg = self.arg;
+        let mut todo_list = Vec::with_capacity(1);
+        let mut literal = self.pos;
+        while let Some(c) = arg[self.pos..].chars().next() {
+            if stop.contains(&c) {
+                Self::literal(&arg[literal..self.pos], &mut todo_list);
+                self.pos += c.len_utf8();
+                return (todo_list, Some(c));
+            }
+            match c {
+                '\\' => {
+                    match arg[self.pos+1..].chars().next() {
+                        Option::Some(next) if stop.contains(&next) => {
+                            Self::literal(&arg[literal..self.pos], &mut todo_list);
+                            todo_list.push(CapGroup::SingleChar(next));
+                            self.pos += 1 + next.len_utf8();
+                            literal = self.pos;
+                        }
+                        // the escape itself is handled by `SPECIAL`, skipping
+                        // `\\` keeps it from escaping a `stop`. Anything else,
+                        // a `%` too, is looked at on its own like before
+                        Option::Some('\\') => self.pos += 2,
+                        _ => self.pos += 1,
+                    };
+                }
+                '%' => {
+                    let start = self.pos;
+                    match self.group() {
+                        Option::None if self.too_deep => return (todo_list, None),
+                        Option::None => self.pos += 1,
+                        Option::Some(group) => {
+                            Self::literal(&arg[literal..start], &mut todo_list);
+                            todo_list.push(group);
+                            literal = self.pos;
+                        }
+                    };
+                }
+                _ => self.pos += c.len_utf8(),
+            };
+        }
+        Self::literal(&arg[literal..], &mut todo_list);
+        (todo_list, None)
+    }
+
+    fn literal(arg: &'a str, todo_list: &mut Vec<CapGroup<'a>>) {
+        if !arg.is_empty() {
+            CapGroup::build_spec_groups(arg, todo_list);
+        }
+    }
+
+    /// Parses the group starting at the current `%`, on failure
+    /// the position is left unchanged.
+    fn group(&mut self) -> Option<CapGroup<'a>> {
+        let arg = self.arg;
+        let start = self.pos;
+        // `%<1:->10>` is padding with `-`, not a default of `>10`
+        let branch = captures_start(&BRANCH, &arg[start..]).filter(|cap| {
+            let rest = cap.get(0).map_or("", |m| &arg[start + m.end()..]);
+            !(cap.name("kind").map(|m| m.as_str()) == Some(":-")
+                && rest.starts_with(&['<', '>', '^'][..])
+                && rest[1..].starts_with(|c: char| c.is_ascii_digit() || c == '.'))
+        });
+        if let Some(cap) = branch.filter(|_| !self.failed.contains(&start)) {
+            if self.depth == MAX_NESTING {
+                self.too_deep = true;
+                return None;
+            }
+            let reference = if let Some(m) = cap.name("labelledgroup") {
+                CapGroup::Labelled(m.as_str())
+            } else {
+                let x = cap.name("multidigit")?.as_str().parse::<usize>().ok()?;
+                CapGroup::MultiDigit(x)
+            };
+            let kind = cap.name("kind")?.as_str();
+            self.pos = start + cap.get(0)?.end();
+            self.depth += 1;
+            let group = if kind == ":-" {
+                match self.template(&['>']) {
+                    (default, Option::Some('>')) => Some(CapGroup::Default(Box::new(reference), default)),
+                    _ => None,
+                }
+            } else {
+                match self.template(&[':', '>']) {
+                    (set, Option::Some('>')) => Some(CapGroup::Conditional(Box::new(reference), set, Vec::new())),
+                    (set, Option::Some(':')) => {
+                        match self.template(&['>']) {
+                            (unset, Option::Some('>')) => Some(CapGroup::Conditional(Box::new(reference), set, unset)),
+                            _ => None,
+                        }
+                    }
+                    _ => None,
+                }
+            };
+            self.depth -= 1;
+            if group.is_none() {
+                self.failed.insert(start);
+                self.pos = start;
+            }
+            if self.depth == 0 {
+                self.too_deep = false;
+            }
+            return group;
+        }
+        match captures_start(&CAP_GROUP_START, &arg[start..]) {
+            Option::Some(cap) => {
+                self.pos = start + cap.get(0)?.end();
+                Some(CapGroup::build(cap))
+            }
+            Option::None => {
+                let (calc, end) = Calculation::parse(&arg[start..])?;
+                self.pos = start + end;
+                Some(CapGroup::Calculated(Box::new(calc)))
+            }
+        }
+    }
+}
+
+/// The groups of an anchored `regex` matching at the start of `arg`. The
+/// match is found first, so the groups are only looked for within it
+/// rather than in all of the rest of the template.
+fn captures_start<'t>(regex: &Regex, arg: &'t str) -> Option<Captures<'t>> {
+    let end = regex.find(arg)?.end();
+    regex.captures(&arg[..end])
+}
+
+/// Where rendered text ends up, a `String` or an `io::Write`
+trait Sink {
+    fn push_str(&mut self, s: &str) -> io::Result<()>;
+}
+impl Sink for String {
+    fn push_str(&mut self, s: &str) -> io::Result<()> {
+        String::push_str(self, s);
+        Ok(())
+    }
+}
+
+struct IoSink<'w,W: Write + ?Sized>(&'w mut W);
+impl<'w,W: Write + ?Sized> Sink for IoSink<'w,W> {
+    fn push_str(&mut self, s: &str) -> io::Result<()> {
+        self.0.write_all(s.as_bytes())
+    }
+}
+
+/// Applies the `\U` `\L` `\u` `\l` case changes which are
+/// in effect to text on its way to the sink.
+struct Cased<'s,S: Sink> {
+    sink: &'s mut S,
+    span: Option<Case>,
+    next: Option<Case>,
+    scratch: String,
+}
+impl<'s,S: Sink> Cased<'s,S> {
+
+    fn new(sink: &'s mut S) -> Self {
+        Cased {
+            sink,
+            span: None,
+            next: None,
+            scratch: String::new(),
+        }
+    }
+
+    fn push_str(&mut self, s: &str) -> io::Result<()> {
+        if s.is_empty() {
+            return Ok(());
+        }
+        if self.span.is_none() && self.next.is_none() {
+            return self.sink.push_str(s);
+        }
+        self.scratch.clear();
+        let mut chars = s.chars();
+        if let Some(case) = self.next.take() {
+            if let Some(c) = chars.next() {
+                case.push_char(c, &mut self.scratch);
+            }
+        }
+        match self.span {
+            Option::None => self.scratch.push_str(chars.as_str()),
+            Option::Some(case) => case.convert(chars.as_str(), &mut self.scratch),
+        };
+        self.sink.push_str(&self.scratch)
+    }
+}
+
+/// Whether `b` may be part of a capture group's name, the
+/// same rule the `regex` crate uses for `(?P<name>..)`.
+pub(crate) fn is_label_byte(b: u8, first: bool) -> bool {
+    b == b'_' || b.is_ascii_alphabetic() || (!first && (b.is_ascii_digit() || b == b'.' || b == b'[' || b == b']'))
+}
+
+/// Levenshtein distance between `a` and `b`, counted in characters.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<char>>();
+    let mut row = (0..=b.len()).collect::<Vec<usize>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let above = row[j + 1];
+            row[j + 1] = (above + 1).min(row[j] + 1).min(diagonal + (ca != cb) as usize);
+            diagonal = above;
+        }
+    }
+    row[b.len()]
+}
+
+fn slice_str(arg: &str, start: usize, end: usize) -> &str {
     unsafe {
         std::str::from_utf8_unchecked(&arg.as_bytes()[start..=end])
     }
@@ -192,11 +718,14 @@ fn slice_str<'a>(arg: &'a str, start: usize, end: usize) -> &'a str {
 
 #[test]
 fn test_cap_group_regex() {
-    const NEEDS_TO_MATCH: &'static [&'static str] = &[
+    const NEEDS_TO_MATCH: &[&str] = &[
         "%1",
         "%<11>",
         "%<group1>",
         "%%<escape>",
+        "%&",
+        "%`",
+        "%'",
     ];
 
     for item in NEEDS_TO_MATCH.iter() {
@@ -205,7 +734,7 @@ fn test_cap_group_regex() {
 }
 #[test]
 fn spec_group_needs_to_match() {
-    const NEEDS_TO_MATCH: &'static [&'static str] = &[
+    const NEEDS_TO_MATCH: &[&str] = &[
         r#"\t"#,
         r#"\n"#,
         r#"\r"#,
@@ -214,6 +743,18 @@ fn spec_group_needs_to_match() {
         r#"\u{2764}"#,
         r#"\x{2764}"#,
         r#"\U{2764}"#,
+        r#"\U"#,
+        r#"\L"#,
+        r#"\E"#,
+        r#"\u"#,
+        r#"\l"#,
+        r#"\0"#,
+        r#"\a"#,
+        r#"\e"#,
+        r#"\f"#,
+        r#"\101"#,
+        r#"\x41"#,
+        r#"\N{EM DASH}"#,
     ];
     for item in NEEDS_TO_MATCH {
         assert!(SPECIAL.is_match(item));
@@ -228,12 +769,21 @@ fn test_cap_group_construction() {
     assert_eq!(group, CapGroup::MultiDigit(201));
     let group = CapGroup::build(CAP_GROUP.captures("%<group6>").unwrap());
     assert_eq!(group, CapGroup::Labelled("group6"));
+    let group = CapGroup::build(CAP_GROUP.captures("%[3]").unwrap());
+    assert_eq!(group, CapGroup::Field(3));
+    let group = CapGroup::build(CAP_GROUP.captures("%{env:HOME}").unwrap());
+    assert_eq!(group, CapGroup::Variable(Variable::Env("HOME")));
     let escaped = CapGroup::build(CAP_GROUP.captures("%%0").unwrap());
     assert_eq!(escaped, CapGroup::Escape("%0"));
 
 
     let group = CapGroup::build(CAP_GROUP.captures("hello world! %1").unwrap());
     assert_eq!(group, CapGroup::SingleDigit(1));
+
+    // too large to be a group, so it's written as is
+    for template in &["%[99999999999999999999999]", "%<99999999999999999999999>", "%<99999999999999999999999:upper>"] {
+        assert_eq!(CapGroup::build_groups(template), vec![CapGroup::CopyFromInput(template)]);
+    }
 }
 
 
@@ -265,3 +815,302 @@ fn test_last_char_breakage() {
     assert_eq!(output[8], CapGroup::CopyFromInput("foobar"));
 }
 
+
+#[test]
+fn test_case_conversion_output() {
+    let regex = Regex::new(r#"(?P<first>\w+) (\w+)"#).unwrap();
+    let caps = regex.captures_from("hello wORLD", 0).unwrap();
+    const DUT: &[(&str, &str)] = &[
+        (r#"\U%1\E %2"#, "HELLO wORLD"),
+        (r#"\u%1 \L%2"#, "Hello world"),
+        (r#"\u\L%2\E!"#, "World!"),
+        (r#"\Ua%<first>\Eb"#, "AHELLOb"),
+        (r#"%<first:upper>-%<2:lower>"#, "HELLO-world"),
+        (r#"%<2:title> %<0:snake> %<0:camel>"#, "World hello_w_orld helloWOrld"),
+        (r#"\U{2764}\u{41}"#, "\u{2764}A"),
+    ];
+    for &(template, expected) in DUT {
+        let groups = CapGroup::build_groups(template);
+        let mut s = String::new();
+        CapGroup::output(&groups, &caps, &Context::default(), &mut s).unwrap();
+        assert_eq!(s, expected, "{}", template);
+        let mut v = Vec::new();
+        CapGroup::steam_output(&groups, &caps, &Context::default(), &mut v).unwrap();
+        assert_eq!(String::from_utf8(v).unwrap(), expected, "{}", template);
+    }
+}
+
+#[test]
+fn test_branch_parsing() {
+    let output = CapGroup::build_groups(r#"a%<1:-none>b%<name?[%<2:-x>\:%1]:->c"#);
+    assert_eq!(output, vec![
+        CapGroup::CopyFromInput("a"),
+        CapGroup::Default(Box::new(CapGroup::MultiDigit(1)), vec![CapGroup::CopyFromInput("none")]),
+        CapGroup::CopyFromInput("b"),
+        CapGroup::Conditional(
+            Box::new(CapGroup::Labelled("name")),
+            vec![
+                CapGroup::CopyFromInput("["),
+                CapGroup::Default(Box::new(CapGroup::MultiDigit(2)), vec![CapGroup::CopyFromInput("x")]),
+                CapGroup::SingleChar(':'),
+                CapGroup::SingleDigit(1),
+                CapGroup::CopyFromInput("]"),
+            ],
+            vec![CapGroup::CopyFromInput("-")]),
+        CapGroup::CopyFromInput("c"),
+    ]);
+
+    // unterminated branches are left as literal text
+    let output = CapGroup::build_groups("%<1?open %2");
+    assert_eq!(output, vec![
+        CapGroup::CopyFromInput("%<1?open "),
+        CapGroup::SingleDigit(2),
+    ]);
+
+    // each is only parsed once, rather than once for every branch it's within
+    let unterminated = "%<1?".repeat(10_000);
+    assert_eq!(CapGroup::build_groups(&unterminated), vec![CapGroup::CopyFromInput(&unterminated)]);
+
+    // branches nested too deep are left as text rather than overflowing the stack
+    let deep = format!("{}x{}", "%<1?".repeat(10_000), ">".repeat(10_000));
+    assert!(matches!(CapGroup::build_groups(&deep)[..],
+                     [CapGroup::CopyFromInput(_), CapGroup::Conditional(..), CapGroup::CopyFromInput(_)]));
+    let nested = format!("{}x{}", "%<1?".repeat(MAX_NESTING), ">".repeat(MAX_NESTING));
+    assert!(matches!(CapGroup::build_groups(&nested)[..], [CapGroup::Conditional(..)]));
+}
+
+#[test]
+fn test_branch_output() {
+    let regex = Regex::new(r#"(?P<key>\w+)(=(?P<value>\w*))?"#).unwrap();
+    const DUT: &[(&str, &str, &str)] = &[
+        ("a=1", "%<key>=%<value:-default>", "a=1"),
+        ("a", "%<key>=%<value:-default>", "a=default"),
+        ("a=", "%<key>=%<value:-%<key:upper>>", "a=A"),
+        ("a=1", "%<value?set to %<value>:unset>", "set to 1"),
+        ("a", "%<value?set to %<value>:unset>", "unset"),
+        ("a", "%<key>%<3?=%3>;", "a;"),
+        ("a=1", r#"%<3?\U%<key>:\L%<key>> %<key>\E %<key>"#, "A A a"),
+        // `-` is a fill character when an alignment and width follow
+        ("a=1", "[%<key:->4>] [%<value:-<3>] [%<key:-^5.1>]", "[---a] [1--] [--a--]"),
+        ("a", "[%<value:->] [%<value:->x>] [%<value:-<b>]", "[] [x>] [<b]"),
+    ];
+    for &(input, template, expected) in DUT {
+        let caps = regex.captures_from(input, 0).unwrap();
+        let groups = CapGroup::build_groups(template);
+        let mut s = String::new();
+        CapGroup::output(&groups, &caps, &Context::default(), &mut s).unwrap();
+        assert_eq!(s, expected, "{} {}", input, template);
+    }
+}
+
+#[test]
+fn test_formatted_output() {
+    let regex = Regex::new(r#"(?P<name>\w+) (\d+)"#).unwrap();
+    let caps = regex.captures_from("widget 42", 0).unwrap();
+    const DUT: &[(&str, &str)] = &[
+        ("[%<1:>10>]", "[    widget]"),
+        ("[%<name:<8.3>]", "[wid     ]"),
+        ("[%<2:0>5>]", "[00042]"),
+        ("[%<name:upper:*^10>]", "[**WIDGET**]"),
+        ("[%<1:.0>]", "[]"),
+        ("[%<2:08>]", "[00000042]"),
+        ("[%<2:05.1>]", "[00004]"),
+    ];
+    for &(template, expected) in DUT {
+        let groups = CapGroup::build_groups(template);
+        let mut s = String::new();
+        CapGroup::output(&groups, &caps, &Context::default(), &mut s).unwrap();
+        assert_eq!(s, expected, "{}", template);
+        let mut v = Vec::new();
+        CapGroup::steam_output(&groups, &caps, &Context::default(), &mut v).unwrap();
+        assert_eq!(String::from_utf8(v).unwrap(), expected, "{}", template);
+    }
+}
+
+#[test]
+fn test_calculated_output() {
+    let regex = Regex::new(r#"(?P<size>\S+) (\d+)"#).unwrap();
+    const DUT: &[(&str, &str, &str)] = &[
+        ("41 2", "%<1+1>", "42"),
+        ("3 2", "%<2*1000>ms", "2000ms"),
+        ("255 2", "%<size:#x> %<1:o> %<2:03>", "0xff 377 002"),
+        ("255 2", "%<2+0:03> %<2:0>3>", "002 002"),
+        ("-42 3", "%<1:05> %<1*1:05> %<2:.2> %<2*1:.2>", "-0042 -0042 3 3.00"),
+        ("7 2", "[%<size/%2:6.2>]", "[  3.50]"),
+        ("7 2", "%<1:-0>%<1?%<1-%2>:none>", "75"),
+    ];
+    for &(input, template, expected) in DUT {
+        let caps = regex.captures_from(input, 0).unwrap();
+        let groups = CapGroup::build_groups(template);
+        let mut s = String::new();
+        CapGroup::output(&groups, &caps, &Context::default(), &mut s).unwrap();
+        assert_eq!(s, expected, "{} {}", input, template);
+    }
+
+    let groups = CapGroup::build_groups("%<size*2> %2");
+    let caps = regex.captures_from("big 2", 0).unwrap();
+    let err = CapGroup::output(&groups, &caps, &Context::default(), &mut String::new()).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(err.to_string(), "'%<size*2>': capture group 'size' is not a number: \"big\"");
+    let ctx = Context { pass_non_numeric: true, ..Context::default() };
+    let mut s = String::new();
+    CapGroup::output(&groups, &caps, &ctx, &mut s).unwrap();
+    assert_eq!(s, "big 2");
+}
+
+#[test]
+fn test_variable_output() {
+    let regex = Regex::new(r#"\w+"#).unwrap();
+    let caps = regex.captures_from("word", 0).unwrap();
+    let ctx = Context {
+        path: "logs/app.log",
+        position: Position { line: 3, match_index: 2, count: 7, offset: 120 },
+        ..Context::default()
+    };
+    std::env::set_var("SSE_TEST_VARIABLE", "set");
+    let groups = CapGroup::build_groups("%{file}:%{line}:%{match} #%{count} @%{offset} %{path} %{env:SSE_TEST_VARIABLE}%{env:SSE_TEST_UNSET} %{nope}");
+    let mut s = String::new();
+    CapGroup::output(&groups, &caps, &ctx, &mut s).unwrap();
+    assert_eq!(s, "app.log:3:2 #7 @120 logs/app.log set %{nope}");
+}
+
+#[test]
+fn test_around_output() {
+    let regex = Regex::new(r#"(\w+)=(\w+)"#).unwrap();
+    let line = "set key=value now";
+    let caps = regex.captures_from(line, 0).unwrap();
+    let m = caps.get(0).unwrap();
+    let ctx = Context {
+        around: Around { before: &line[..m.start()], after: &line[m.end()..] },
+        ..Context::default()
+    };
+    let groups = CapGroup::build_groups("%'|%2=%1|%`<%&> %%&");
+    let mut s = String::new();
+    CapGroup::output(&groups, &caps, &ctx, &mut s).unwrap();
+    assert_eq!(s, " now|value=key|set <key=value> %&");
+}
+
+#[test]
+fn test_escape_output() {
+    let regex = Regex::new(r#"x"#).unwrap();
+    let caps = regex.captures_from("x", 0).unwrap();
+    const DUT: &[(&str, &str)] = &[
+        (r#"\t"#, "\t"),
+        (r#"\n"#, "\n"),
+        (r#"\r"#, "\r"),
+        (r#"\v"#, "\u{0B}"),
+        (r#"\\"#, "\\"),
+        (r#"\0"#, "\0"),
+        (r#"\a"#, "\u{07}"),
+        (r#"\e"#, "\u{1B}"),
+        (r#"\f"#, "\u{0C}"),
+        (r#"\101\060"#, "A0"),
+        (r#"\0101"#, "\u{08}1"),
+        (r#"\x41\x7e"#, "A~"),
+        (r#"\xe9\351"#, r#"\xe9\351"#),
+        (r#"\u{2764}\x{41}\U{1F600}"#, "\u{2764}A\u{1F600}"),
+        (r#"\N{EM DASH}\N{latin small letter a}"#, "\u{2014}a"),
+        (r#"\N{NOT A CHARACTER NAME}"#, r#"\N{NOT A CHARACTER NAME}"#),
+        (r#"\u{D800}"#, r#"\u{D800}"#),
+        (r#"a\\tb"#, "a\\tb"),
+        // a backslash doesn't escape a group
+        (r#"\%0 \\%0 \%%0 \%<0>"#, r#"\x \x \%0 \x"#),
+    ];
+    for &(template, expected) in DUT {
+        let groups = CapGroup::build_groups(template);
+        let mut s = String::new();
+        CapGroup::output(&groups, &caps, &Context::default(), &mut s).unwrap();
+        assert_eq!(s, expected, "{}", template);
+    }
+
+    // bytes above 0x7F are rejected before they get this far
+    assert_eq!(CapGroup::find_byte_escape(r#"\x41\x7f\177\u{e9}"#), None);
+    assert_eq!(CapGroup::find_byte_escape(r#"%1\x41\xE9"#), Some(r#"\xE9"#));
+    assert_eq!(CapGroup::find_byte_escape(r#"\\x80 \200"#), Some(r#"\200"#));
+}
+
+#[test]
+fn test_check_references() {
+    assert_eq!(edit_distance("nmae", "name"), 2);
+    assert_eq!(edit_distance("", "abc"), 3);
+    assert_eq!(edit_distance("kitten", "sitting"), 3);
+
+    let regex = Regex::new(r#"(?P<name>\w+) (?P<value>\d+)"#).unwrap();
+    let groups = CapGroup::build_groups("%0 %2 %<name> %<value:upper> %<name?%<value:-x>>");
+    assert!(CapGroup::check_references(&groups, &regex).is_empty());
+
+    let groups = CapGroup::build_groups("%7 %<nmae> %<zzzzzz:-%<11>> %<value+%<valeu>>");
+    assert_eq!(CapGroup::check_references(&groups, &regex), vec![
+        "the format string references group '7' but the regex only has groups 0 to 2".to_string(),
+        "the format string references group 'nmae' but the regex has no group by that name, did you mean 'name'?".to_string(),
+        "the format string references group 'zzzzzz' but the regex has no group by that name".to_string(),
+        "the format string references group '11' but the regex only has groups 0 to 2".to_string(),
+        "the format string references group 'valeu' but the regex has no group by that name, did you mean 'value'?".to_string(),
+    ]);
+}
+
+#[test]
+fn test_label_names() {
+    const NEEDS_TO_MATCH: &[(&str, &str)] = &[
+        ("%<x>", "x"),
+        ("%<Name>", "Name"),
+        ("%<first_name>", "first_name"),
+        ("%<_private>", "_private"),
+        ("%<a.b[0]>", "a.b[0]"),
+    ];
+    for &(template, label) in NEEDS_TO_MATCH {
+        assert_eq!(CapGroup::build_groups(template), vec![CapGroup::Labelled(label)], "{}", template);
+    }
+    assert_eq!(CapGroup::build_groups("%<x:upper>"), vec![CapGroup::Converted(Box::new(CapGroup::Labelled("x")), Case::Upper)]);
+    assert_eq!(CapGroup::build_groups("%<1nope>"), vec![CapGroup::CopyFromInput("%<1nope>")]);
+
+    let regex = Regex::new(r#"(?P<x>\w) (?P<First_name>\w+) (?P<n2>\d+)"#).unwrap();
+    let caps = regex.captures_from("a bob 5", 0).unwrap();
+    let groups = CapGroup::build_groups("%<x>|%<First_name:upper>|%<n2*2>|%<x?set>");
+    let mut s = String::new();
+    CapGroup::output(&groups, &caps, &Context::default(), &mut s).unwrap();
+    assert_eq!(s, "a|BOB|10|set");
+    assert!(CapGroup::check_references(&groups, &regex).is_empty());
+}
+
+#[test]
+fn test_native_ignores_other_references() {
+    // `$` and `\g` are only references with `--syntax`
+    let regex = Regex::new(r#"(?P<first>\w+) (?P<last>\w+)"#).unwrap();
+    let caps = regex.captures_from("ada lovelace", 0).unwrap();
+    for &(template, expected) in &[
+        ("costs $5", "costs $5"),
+        ("a$$b", "a$$b"),
+        (r#"${last} $1 \g<first> %<last>"#, r#"${last} $1 \g<first> lovelace"#),
+    ] {
+        let groups = CapGroup::build_groups(template);
+        let mut s = String::new();
+        CapGroup::output(&groups, &caps, &Context::default(), &mut s).unwrap();
+        assert_eq!(s, expected, "{}", template);
+    }
+}
+
+#[test]
+fn test_mapped_output() {
+    let map = Mapping::parse("oldName,newName\nfoo,bar\n", ',').unwrap();
+    let regex = Regex::new(r#"(?P<id>\w+)"#).unwrap();
+    let groups = CapGroup::build_groups("%<id:map>|%<1:map:upper>|%<1:map:>8>");
+    assert!(CapGroup::uses_map(&groups));
+    assert!(!CapGroup::uses_map(&CapGroup::build_groups("%<1?%<1:upper>>")));
+    assert!(CapGroup::uses_map(&CapGroup::build_groups("%<1?%<1:map>>")));
+
+    let ctx = Context { map: Some(&map), ..Context::default() };
+    let caps = regex.captures_from("oldName", 0).unwrap();
+    let mut s = String::new();
+    CapGroup::output(&groups, &caps, &ctx, &mut s).unwrap();
+    assert_eq!(s, "newName|NEWNAME| newName");
+
+    let caps = regex.captures_from("other", 0).unwrap();
+    let mut s = String::new();
+    CapGroup::output(&groups, &caps, &ctx, &mut s).unwrap();
+    assert_eq!(s, "other|OTHER|   other");
+
+    let ctx = Context { map_strict: true, ..ctx };
+    let err = CapGroup::output(&groups, &caps, &ctx, &mut String::new()).unwrap_err();
+    assert_eq!(err.to_string(), "\"other\" isn't a key in the '--map' file");
+}
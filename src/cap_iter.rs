@@ -1,29 +1,39 @@
 use std::collections::VecDeque;
 use std::io::{self,Write};
-use regex::{CaptureMatches,Captures};
+use crate::{
+    cap_groups::{CapGroup,Context,Position,Around},
+    matcher::{Matcher,CaptureMatches,Captures},
+};
 
-use crate::cap_groups::CapGroup;
 
-
-pub struct CapIter<'a,'r> {
+pub struct CapIter<'a> {
     buffer: &'a str,
-    caps: CaptureMatches<'r,'a>,
+    caps: CaptureMatches<'a>,
+    /// the match after the current one, its start is where `%'` ends
+    peeked: Option<Captures<'a>>,
     caps_done: bool,
     old_start: usize,
     nice: bool,
-    queue: VecDeque<CapOut<'a>>
+    queue: VecDeque<CapOut<'a>>,
+    position: Position,
+    /// `position.line` is the line `buffer[lines_counted]` is on
+    lines_counted: usize,
 }
-impl<'a,'r> CapIter<'a,'r> {
-    pub fn new(buffer: &'a str, caps: CaptureMatches<'r,'a>, nice: bool) -> Self {
+impl<'a> CapIter<'a> {
+    pub fn new(buffer: &'a str, matcher: &'a dyn Matcher, nice: bool) -> Self {
         CapIter {
-            buffer, nice, caps,
+            buffer, nice,
+            caps: CaptureMatches::new(matcher, buffer),
+            peeked: None,
             caps_done: false,
             old_start: 0,
             queue: VecDeque::with_capacity(2),
+            position: Position { line: 1, ..Position::default() },
+            lines_counted: 0,
         }
     }
 }
-impl<'a,'r> Iterator for CapIter<'a,'r> {
+impl<'a> Iterator for CapIter<'a> {
     type Item = CapOut<'a>;
     fn next(&mut self) -> Option<Self::Item> {
         loop {
@@ -44,31 +54,34 @@ impl<'a,'r> Iterator for CapIter<'a,'r> {
 
 pub enum CapOut<'a> {
     CopyText(&'a str),
-    Group(Captures<'a>),
+    Group(Captures<'a>, Position, Around<'a>),
 }
 impl<'a> CapOut<'a> {
 
-    pub fn output<'b>(&self, arg: &[CapGroup<'b>], buffer: &mut String) {
-        match self {
-            &Self::CopyText(s) => buffer.push_str(s),
-            &Self::Group(ref caps) => CapGroup::output(arg, caps, buffer),
+    pub fn output<'b>(&self, arg: &[CapGroup<'b>], ctx: &Context<'_>, buffer: &mut String) -> io::Result<()> {
+        match *self {
+            Self::CopyText(s) => {
+                buffer.push_str(s);
+                Ok(())
+            }
+            Self::Group(ref caps, position, around) => CapGroup::output(arg, caps, &Context { position, around, ..*ctx }, buffer),
         }
     }
 
-    pub fn stream_output<'b,W: Write>(&self, arg: &[CapGroup<'b>], output: &mut W) -> io::Result<()> {
-        match self {
-            &Self::CopyText(s) => output.write_all(s.as_bytes()),
-            &Self::Group(ref caps) => CapGroup::steam_output(arg, caps, output),
+    pub fn stream_output<'b,W: Write + ?Sized>(&self, arg: &[CapGroup<'b>], ctx: &Context<'_>, output: &mut W) -> io::Result<()> {
+        match *self {
+            Self::CopyText(s) => output.write_all(s.as_bytes()),
+            Self::Group(ref caps, position, around) => CapGroup::steam_output(arg, caps, &Context { position, around, ..*ctx }, output),
         }
     }
 }
 
-impl<'a,'r> CapIter<'a,'r> {
+impl<'a> CapIter<'a> {
     fn advance_one_capture(&mut self) {
         if self.caps_done {
             return;
         }
-        let cap = match self.caps.next() {
+        let cap = match self.peeked.take().or_else(|| self.caps.next()) {
             Option::None => {
                 self.caps_done = true;
                 if (self.old_start+1) <= self.buffer.len() && self.nice {
@@ -86,14 +99,65 @@ impl<'a,'r> CapIter<'a,'r> {
         if start != self.old_start && self.nice {
             self.queue.push_back(CapOut::CopyText(slice_str(self.buffer,self.old_start,start-1)));
         }
+        self.peeked = self.caps.next();
+        let next_start = match self.peeked.as_ref().and_then(|cap| cap.get(0)) {
+            Option::None => self.buffer.len(),
+            Option::Some(m) => m.start(),
+        };
+        let around = Around {
+            before: &self.buffer[self.old_start..start],
+            after: &self.buffer[end..next_start],
+        };
         self.old_start = end;
-        self.queue.push_back(CapOut::Group(cap));
+        let lines = self.buffer.as_bytes()[self.lines_counted..start].iter().filter(|&&b| b == b'\n').count();
+        if lines != 0 {
+            self.position.line += lines;
+            self.position.match_index = 0;
+        }
+        self.lines_counted = start;
+        self.position.match_index += 1;
+        self.position.count += 1;
+        self.position.offset = start;
+        self.queue.push_back(CapOut::Group(cap, self.position, around));
     }
 }
 
 
-fn slice_str<'a>(arg: &'a str, start: usize, end: usize) -> &'a str {
+fn slice_str(arg: &str, start: usize, end: usize) -> &str {
     unsafe {
         std::str::from_utf8_unchecked(&arg.as_bytes()[start..=end])
     }
 }
+
+#[test]
+fn test_cap_iter_positions() {
+    let regex = regex::Regex::new(r#"\d+"#).unwrap();
+    let buffer = "1 2\n\n3 4 5\n6";
+    let positions = CapIter::new(buffer, &regex, false)
+        .filter_map(|item| match item {
+            CapOut::Group(caps, position, _) => Some((caps.get(0).unwrap().as_str(), position.line, position.match_index, position.count, position.offset)),
+            CapOut::CopyText(_) => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(positions, vec![
+        ("1", 1, 1, 1, 0),
+        ("2", 1, 2, 2, 2),
+        ("3", 3, 1, 3, 5),
+        ("4", 3, 2, 4, 7),
+        ("5", 3, 3, 5, 9),
+        ("6", 4, 1, 6, 11),
+    ]);
+}
+
+#[test]
+fn test_cap_iter_around() {
+    let regex = regex::Regex::new(r#"\d+"#).unwrap();
+    let buffer = "a1bb22c";
+    let around = CapIter::new(buffer, &regex, true)
+        .filter_map(|item| match item {
+            CapOut::Group(_, _, around) => Some((around.before, around.after)),
+            CapOut::CopyText(_) => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(around, vec![("a", "bb"), ("bb", "c")]);
+}
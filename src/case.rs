@@ -0,0 +1,134 @@
+
+/// Case conversions available within a format string.
+#[derive(Clone,Copy,PartialEq,Eq,PartialOrd,Ord,Hash,Debug)]
+pub enum Case {
+    /// `HELLO WORLD`
+    Upper,
+    /// `hello world`
+    Lower,
+    /// `Hello World`
+    Title,
+    /// `hello_world`
+    Snake,
+    /// `helloWorld`
+    Camel,
+}
+impl Case {
+
+    pub fn from_name(name: &str) -> Option<Case> {
+        match name {
+            "upper" => Some(Case::Upper),
+            "lower" => Some(Case::Lower),
+            "title" => Some(Case::Title),
+            "snake" => Some(Case::Snake),
+            "camel" => Some(Case::Camel),
+            _ => None,
+        }
+    }
+
+    /// writes `c` to `buffer` in this case, only `Upper` and `Lower`
+    /// make sense for a single character, everything else is `Upper`
+    pub fn push_char(&self, c: char, buffer: &mut String) {
+        match self {
+            &Case::Lower => buffer.extend(c.to_lowercase()),
+            _ => buffer.extend(c.to_uppercase()),
+        }
+    }
+
+    pub fn convert(&self, arg: &str, buffer: &mut String) {
+        match *self {
+            Case::Upper => buffer.push_str(&arg.to_uppercase()),
+            Case::Lower => buffer.push_str(&arg.to_lowercase()),
+            Case::Title => {
+                // separators are kept, only the letters change
+                let mut start_of_word = true;
+                for c in arg.chars() {
+                    if c.is_alphanumeric() {
+                        if start_of_word {
+                            buffer.extend(c.to_uppercase());
+                        } else {
+                            buffer.extend(c.to_lowercase());
+                        }
+                        start_of_word = false;
+                    } else {
+                        buffer.push(c);
+                        start_of_word = true;
+                    }
+                }
+            }
+            Case::Snake => {
+                for (pos, word) in words(arg).into_iter().enumerate() {
+                    if pos != 0 {
+                        buffer.push('_');
+                    }
+                    buffer.push_str(&word.to_lowercase());
+                }
+            }
+            Case::Camel => {
+                for (pos, word) in words(arg).into_iter().enumerate() {
+                    let mut chars = word.chars();
+                    if pos == 0 {
+                        buffer.push_str(&word.to_lowercase());
+                    } else if let Some(first) = chars.next() {
+                        buffer.extend(first.to_uppercase());
+                        buffer.push_str(&chars.as_str().to_lowercase());
+                    }
+                }
+            }
+        };
+    }
+}
+
+/// Splits `arg` into words on anything that isn't a letter or digit, and
+/// on case changes so `HTTPServer`, `http_server` and `http-server` all
+/// produce `HTTP`/`http` and `Server`/`server`.
+fn words(arg: &str) -> Vec<&str> {
+    let mut words = Vec::new();
+    let chars = arg.char_indices().collect::<Vec<_>>();
+    let mut start: Option<usize> = None;
+    for (i, &(pos, c)) in chars.iter().enumerate() {
+        if !c.is_alphanumeric() {
+            if let Some(s) = start.take() {
+                words.push(&arg[s..pos]);
+            }
+            continue;
+        }
+        match start {
+            Option::None => start = Some(pos),
+            Option::Some(s) => {
+                let prev = chars[i - 1].1;
+                let next = chars.get(i + 1).map(|&(_, n)| n);
+                let boundary = c.is_uppercase() && (prev.is_lowercase() || prev.is_numeric())
+                    || c.is_uppercase() && prev.is_uppercase() && next.map(|n| n.is_lowercase()).unwrap_or(false);
+                if boundary {
+                    words.push(&arg[s..pos]);
+                    start = Some(pos);
+                }
+            }
+        };
+    }
+    if let Some(s) = start {
+        words.push(&arg[s..]);
+    }
+    words
+}
+
+#[test]
+fn test_case_conversions() {
+    const DUT: &[(&str, Case, &str)] = &[
+        ("hello World", Case::Upper, "HELLO WORLD"),
+        ("Hello WORLD", Case::Lower, "hello world"),
+        ("hello wORLD-foo_bar", Case::Title, "Hello World-Foo_Bar"),
+        ("HelloWorld", Case::Snake, "hello_world"),
+        ("HTTPServer error2Log", Case::Snake, "http_server_error2_log"),
+        ("already_snake_case", Case::Snake, "already_snake_case"),
+        ("hello-big world", Case::Camel, "helloBigWorld"),
+        ("SOME_CONSTANT", Case::Camel, "someConstant"),
+        ("", Case::Camel, ""),
+    ];
+    for &(input, case, expected) in DUT {
+        let mut s = String::new();
+        case.convert(input, &mut s);
+        assert_eq!(s, expected, "{:?} {:?}", case, input);
+    }
+}
@@ -0,0 +1,133 @@
+
+use std::io::{self,BufRead,Read};
+
+#[cfg(test)]
+use proptest::prelude::*;
+#[cfg(test)]
+use crate::buffered_reader::{SplitIterator,Trickle};
+use crate::cap_groups::Position;
+
+/// `--chunked` without a size.
+pub const DEFAULT_CHUNK_SIZE: usize = 1 << 20;
+
+/// A piece of a larger input which ends with a line terminator, or
+/// at the end of the input, so its lines can be matched on their own.
+#[derive(Clone,PartialEq,Eq,Debug)]
+pub struct Chunk {
+    pub text: Vec<u8>,
+    /// the lines and bytes of the input before this chunk
+    pub start: Position,
+}
+
+/// Splits an input into chunks of about `size` bytes, each is read up
+/// to `size` then on to the end of the line. The terminators are found
+/// exactly where splitting the whole input would find them, none of
+/// them can overlap with itself so the last one found is never part of
+/// an earlier one.
+pub struct Chunks<R: BufRead> {
+    reader: R,
+    term: &'static [u8],
+    size: usize,
+    start: Position,
+    end: bool,
+}
+impl<R: BufRead> Chunks<R> {
+    pub fn new(reader: R, term: &'static [u8], size: usize) -> Chunks<R> {
+        Chunks { reader, term, size: size.max(1), start: Position::default(), end: false }
+    }
+
+    fn read_chunk(&mut self) -> io::Result<Vec<u8>> {
+        let mut text = Vec::with_capacity(self.size + 256);
+        (&mut self.reader).take(self.size as u64).read_to_end(&mut text)?;
+        if text.len() < self.size {
+            self.end = true;
+            return Ok(text);
+        }
+        let last = self.term[self.term.len() - 1];
+        while !text.ends_with(self.term) {
+            if self.reader.read_until(last, &mut text)? == 0 {
+                self.end = true;
+                break;
+            }
+        }
+        Ok(text)
+    }
+}
+impl<R: BufRead> Iterator for Chunks<R> {
+    type Item = io::Result<Chunk>;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.end {
+            return None;
+        }
+        let text = match self.read_chunk() {
+            Ok(text) => text,
+            Err(e) => {
+                self.end = true;
+                return Some(Err(e));
+            }
+        };
+        if text.is_empty() {
+            return None;
+        }
+        let start = self.start;
+        self.start.line += count_terms(&text, self.term);
+        self.start.offset += text.len();
+        Some(Ok(Chunk { text, start }))
+    }
+}
+
+/// How many terminators are in `text`, which is how many lines end in it.
+fn count_terms(text: &[u8], term: &[u8]) -> usize {
+    let last = term[term.len() - 1];
+    if term.len() == 1 {
+        return text.iter().filter(|&&b| b == last).count();
+    }
+    text.iter()
+        .enumerate()
+        .filter(|&(pos, &b)| b == last && text[..=pos].ends_with(term))
+        .count()
+}
+
+#[test]
+fn test_chunks() {
+    const TERM: &[u8] = b"\r\n";
+    let input = "a\r\nb\rc\n\r\nlonger line\r\n\r\nend".repeat(5);
+    for size in 1..20 {
+        let chunks = Chunks::new(input.as_bytes(), TERM, size)
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+        let mut whole = Vec::new();
+        for chunk in chunks.iter() {
+            let before = &input.as_bytes()[..whole.len()];
+            assert_eq!(chunk.start.offset, before.len());
+            assert_eq!(chunk.start.line, SplitIterator::new(before, TERM).filter(|&(_, eol)| eol).count());
+            whole.extend_from_slice(&chunk.text);
+            assert!(chunk.text.ends_with(TERM) || whole.len() == input.len(), "{} {:?}", size, chunk);
+        }
+        assert_eq!(whole, input.as_bytes());
+    }
+    assert_eq!(Chunks::new(&b""[..], TERM, 8).count(), 0);
+}
+
+#[cfg(test)]
+proptest! {
+    #[test]
+    fn test_chunk_lines_match_split_iterator(
+        data in prop::collection::vec(prop::sample::select(vec![b'a', b'\r', b'\n', 0x15, 0x1e]), 0..200),
+        eol in prop::sample::select(vec![&b"\n"[..], b"\r", b"\r\n", b"\n\r", b"\x15", b"\x1e"]),
+        size in 1..24usize,
+        sizes in prop::collection::vec(1..8usize, 1..8),
+    ) {
+        let trickle = Trickle { data: &data, sizes, reads: 0 };
+        let mut lines = Vec::new();
+        for chunk in Chunks::new(io::BufReader::with_capacity(1, trickle), eol, size) {
+            let chunk = chunk.unwrap();
+            prop_assert_eq!(chunk.start.line, lines.iter().filter(|&&(_, eol)| eol).count());
+            lines.extend(SplitIterator::new(&chunk.text, eol).map(|(line, eol)| (line.to_vec(), eol)));
+        }
+        let expected = SplitIterator::new(&data, eol)
+            .map(|(line, eol)| (line.to_vec(), eol))
+            .collect::<Vec<_>>();
+        prop_assert_eq!(lines, expected);
+    }
+}
@@ -3,25 +3,36 @@ use std::io::{self,Read,BufReader,BufWriter};
 use std::borrow::Cow;
 
 use lazy_static::lazy_static;
-use regex::{Regex,Captures,RegexBuilder};
+use regex::{Regex,Captures,RegexBuilder,RegexSetBuilder};
 
+#[cfg(feature = "fancy")]
+use crate::matcher::Fancy;
 use crate::{
     cap_groups::{CapGroup},
-    buffered_reader::BufferedReader,
-    work::{MyTrait,do_work},
+    chunks::DEFAULT_CHUNK_SIZE,
+    delimited::{Quoting,build_row},
+    json::JsonPath,
+    syntax::Syntax,
+    literals::Literals,
+    matcher::{Matcher,Literal},
+    pcre::{Target,translate},
+    mapping::load_pairs,
+    rules::{Rules,SET_DFA_SIZE_LIMIT},
+    work::{MyTrait,do_work,do_literals,do_rules},
 };
 
 
 lazy_static! {
-    static ref INITIAL_FLAG_MATCH: Regex = Regex::new(r#"^(?P<LeadingHypen>-)?((?P<stdin>i)|(?P<file>f))((?P<LiteralMatch>F)|(?P<nice>n)|(?P<CaseInSensitive>S)|(?P<IgnoreWhiteSpace>x)|(?P<SwapGreedy>G)|(?P<DotMatchesNewLine>s)|(?P<Ascii>a))*((?P<Continuous>c)|(?P<LineByLine>l((?P<WindowsEoL>w)|(?P<MacEoL>m)|(?P<UnixEoL>u)|(?P<IBM>i)|(?P<QNX>q)(?P<Acorn>a))?))?(?P<output>(?P<stdout>o)|(?P<stderr>e)|(?P<writeback>f)|(?P<redirect>r))?$"#).unwrap();
+    static ref INITIAL_FLAG_MATCH: Regex = Regex::new(r#"^(?P<LeadingHypen>-)?((?P<stdin>i)|(?P<file>f))((?P<LiteralMatch>F)|(?P<nice>n)|(?P<CaseInSensitive>S)|(?P<IgnoreWhiteSpace>x)|(?P<SwapGreedy>G)|(?P<DotMatchesNewLine>s)|(?P<Ascii>a))*((?P<Continuous>c)|(?P<LineByLine>l((?P<WindowsEoL>w)|(?P<MacEoL>m)|(?P<UnixEoL>u)|(?P<IBM>i)|(?P<QNX>q)|(?P<Acorn>a))?))?(?P<output>(?P<stdout>o)|(?P<stderr>e)|(?P<writeback>f)|(?P<redirect>r))?$"#).unwrap();
     static ref HELP: Regex = Regex::new(r#"^-?-[hH]([eE][lL][pP])?$"#).unwrap();
     static ref VERSION: Regex = Regex::new(r#"^-?-[vV](ersion)?$"#).unwrap();
+    static ref LONG_OPTION: Regex = Regex::new(r#"(?s)^--(?P<name>[a-z][a-z\-]*)(=(?P<value>.*))?$"#).unwrap();
 }
 
 pub fn from_cli() -> Result<WorkTodo,Cow<'static,str>> {
     let args = std::env::args().collect::<Vec<String>>();
     if args.len() <= 1 {
-        return Err(Cow::Borrowed("run --help for help"));
+        Err(Cow::Borrowed("run --help for help"))
     } else {
         if HELP.is_match(&args[1]) {
             return Ok(WorkTodo::PrintHelp);
@@ -30,7 +41,85 @@ pub fn from_cli() -> Result<WorkTodo,Cow<'static,str>> {
             return Ok(WorkTodo::PrintVersion);
         }
         if INITIAL_FLAG_MATCH.is_match(&args[1]) {
-            let opts = InitialFlagOptions::new(&INITIAL_FLAG_MATCH.captures(&args[1]).unwrap());
+            let mut opts = InitialFlagOptions::new(&INITIAL_FLAG_MATCH.captures(&args[1]).unwrap());
+
+            // long options sit between the flags and the regex,
+            // `--` ends them early so a regex may start with `--`
+            let mut pos = 2usize;
+            while pos < args.len() {
+                if args[pos] == "--" {
+                    pos += 1;
+                    break;
+                }
+                match LONG_OPTION.captures(&args[pos]) {
+                    Option::None => break,
+                    Option::Some(ref cap) => opts.long_option(cap).map_err(Cow::from)?,
+                };
+                pos += 1;
+            }
+            opts.validate().map_err(Cow::from)?;
+            let mut args = args;
+            args.drain(2..pos);
+
+            // text read by `--regex-file` or `--format-file`
+            // takes the place of its argument
+            if let Some(ref path) = opts.regex_file {
+                let mut text = read_text(path)
+                    .map_err(|e| Cow::from(format!("couldn't read the regex from '{}': {}", path, e)))?;
+                if text.ends_with('\n') {
+                    text.pop();
+                    if text.ends_with('\r') {
+                        text.pop();
+                    }
+                }
+                args.insert(2.min(args.len()), text);
+            }
+            if let Some(ref path) = opts.format_file {
+                // it follows the regex, which has to be there
+                if args.len() < 3 {
+                    return Err(Cow::from(format!("required at least 2 args: '{} [REGEXP]' see '--help' for more info", &args[1])));
+                }
+                let text = read_text(path)
+                    .map_err(|e| Cow::from(format!("couldn't read the format string from '{}': {}", path, e)))?;
+                args.insert(3, text);
+            }
+
+            // the pairs take the place of both the regex and
+            // the format string, so files directly follow the options
+            if let Some(ref path) = opts.literals {
+                let literals = Literals::load(path, opts.case_in_sensitive, opts.whole_words)
+                    .map_err(|e| match e.kind() {
+                        // already names the file
+                        io::ErrorKind::InvalidData => Cow::from(format!("'--literals' file {}", e)),
+                        _ => Cow::from(format!("couldn't read '{}': {}", path, e)),
+                    })?;
+                let optional_args = opts.file_args(&args, 2)?;
+                do_literals(&opts, &literals, &optional_args)
+                    .map_err(|e| Cow::Owned(e.to_string()))?;
+                return Ok(WorkTodo::Nothing);
+            }
+
+            // every row is a regex and its format string, so like
+            // `--literals` files directly follow the options
+            if let Some(ref path) = opts.rules {
+                let pairs = load_pairs(path)
+                    .map_err(|e| match e.kind() {
+                        io::ErrorKind::InvalidData => Cow::from(format!("'--rules' file {}", e)),
+                        _ => Cow::from(format!("couldn't read '{}': {}", path, e)),
+                    })?;
+                let rules = opts.build_rules(&pairs).map_err(Cow::from)?;
+                let mut caps = Vec::with_capacity(pairs.len());
+                for (pos, (_, format)) in pairs.iter().enumerate() {
+                    let groups = opts.syntax.build_groups(format);
+                    opts.check_groups(format, &groups, rules.regex(pos), &format!("rule {}: ", pos + 1))?;
+                    caps.push(groups);
+                }
+                let optional_args = opts.file_args(&args, 2)?;
+                do_rules(&opts, &rules, &caps, &optional_args)
+                    .map_err(|e| Cow::Owned(e.to_string()))?;
+                return Ok(WorkTodo::Nothing);
+            }
+
             let regex = if args.len() < 3 {
                 return Err(Cow::from(format!("required at least 2 args: '{} [REGEXP]' see '--help' for more info", &args[1])));
             } else {
@@ -39,51 +128,65 @@ pub fn from_cli() -> Result<WorkTodo,Cow<'static,str>> {
                     Err(e) => return Err(Cow::from(e))
                 }
             };
-            let caps = if args.len() < 4 {
-                return Err(Cow::from(format!("required at least 3 args: '{} {} [FORMAT STRING]' see '--help' for more info", &args[1], &args[2])));
-            } else {
-                if opts.literal_match {
-                    vec![CapGroup::CopyFromInput(&args[3])]
-                } else {
-                    CapGroup::build_groups(&args[3])
-                }
-            };
 
-            let optional_args = match opts.additional_args_needed() {
-                1 => {
-                    let mut v = Vec::with_capacity(1);
-                    if args.len() < 5 {
-                        return Err(Cow::from(format!("required at least 4 args: '{} {} {} [FILE]' see '--help' for more info", &args[1], &args[2], &args[3])));
-                    } else {
-                        v.push(args[4].to_string());
-                    }
-                    v
-                },
-                2 => {
-                    let mut v = Vec::with_capacity(2);
-                    if args.len() < 5 {
-                        return Err(Cow::from(format!("required at least 5 args: '{} {} {} [FILE IN] [FILE OUT]' see '--help' for more info", &args[1], &args[2], &args[3])));
-                    } else {
-                        v.push(args[4].to_string());
-                    }
-                    if args.len() < 6 {
-                        return Err(Cow::from(format!("required at least 4 args: '{} {} {} {} [FILE OUT]' see '--help' for more info", &args[1], &args[2], &args[3], &args[4])));
+            // in delimited mode there is no format string,
+            // so any file arguments directly follow the regex
+            let (caps, header, files_at) = match opts.delimited {
+                Option::Some(quoting) => {
+                    let record_end = opts.matching.is_multi_line().then_some('\n');
+                    let (caps, header) = build_row(&opts.fields, quoting, regex.as_ref(), record_end)
+                        .map_err(Cow::from)?;
+                    (caps, opts.header.then_some(header), 3)
+                }
+                Option::None => {
+                    let caps = if args.len() < 4 {
+                        return Err(Cow::from(format!("required at least 3 args: '{} {} [FORMAT STRING]' see '--help' for more info", &args[1], &args[2])));
                     } else {
-                        v.push(args[5].to_string());
-                    }
-                    v
+                        if opts.literal_match {
+                            vec![CapGroup::CopyFromInput(&args[3])]
+                        } else {
+                            opts.syntax.build_groups(&args[3])
+                        }
+                    };
+                    opts.check_groups(&args[3], &caps, regex.as_ref(), "")?;
+                    (caps, None, 4)
                 }
-                _ => Vec::new(),
             };
-            do_work(&opts, &regex, &caps, &optional_args)
-                .map_err(|e| Cow::Owned(format!("{:?}", e)))?;
-            return Ok(WorkTodo::Nothing);
+
+            let optional_args = opts.file_args(&args, files_at)?;
+            do_work(&opts, regex.as_ref(), &caps, header.as_deref(), &optional_args)
+                .map_err(|e| Cow::Owned(e.to_string()))?;
+            Ok(WorkTodo::Nothing)
         } else {
-            return Err(Cow::Borrowed("didn't understand that, see: '--help' for more info"));
+            Err(Cow::Borrowed("didn't understand that, see: '--help' for more info"))
         }
     }
 }
 
+/// a number of bytes, optionally followed by `K` or `M`
+fn parse_size(text: &str) -> Option<usize> {
+    let (digits, scale) = match text.as_bytes().last() {
+        Option::Some(b'K') | Option::Some(b'k') => (&text[..text.len() - 1], 1 << 10),
+        Option::Some(b'M') | Option::Some(b'm') => (&text[..text.len() - 1], 1 << 20),
+        _ => (text, 1),
+    };
+    match digits.parse::<usize>() {
+        Ok(x) if x > 0 => x.checked_mul(scale),
+        _ => None,
+    }
+}
+
+/// reads all of `path`, `-` is stdin
+fn read_text(path: &str) -> io::Result<String> {
+    if path == "-" {
+        let mut s = String::new();
+        io::stdin().read_to_string(&mut s)?;
+        Ok(s)
+    } else {
+        std::fs::read_to_string(path)
+    }
+}
+
 pub enum WorkTodo {
     PrintHelp,
     PrintVersion,
@@ -105,6 +208,27 @@ pub struct InitialFlagOptions {
     swap_greedy: bool,
     dot_matches_newline: bool,
     ascii_only: bool,
+    pub delimited: Option<Quoting>,
+    pub fields: String,
+    pub header: bool,
+    pub json: Option<JsonPath>,
+    pub json_keys: bool,
+    pub columns: Option<Vec<usize>>,
+    pub delimiter: char,
+    pub pass_non_numeric: bool,
+    lenient: bool,
+    syntax: Syntax,
+    regex_file: Option<String>,
+    format_file: Option<String>,
+    pub map: Option<String>,
+    pub map_strict: bool,
+    pub literals: Option<String>,
+    whole_words: bool,
+    fancy: bool,
+    rules: Option<String>,
+    pub threads: usize,
+    pub chunk_size: Option<usize>,
+    pub mmap: bool,
 }
 impl InitialFlagOptions {
 
@@ -114,15 +238,107 @@ impl InitialFlagOptions {
             (&Input::Stdin,&Output::DifferentFile) => 1,
             (&Input::File,&Output::SameFile) => 1,
             (&Input::File,&Output::DifferentFile) => 2,
+            (&Input::File,_) => 1,
             _ => 0,
         }
     }
 
-    fn build_regex(&self, arg: &str) -> Result<Regex,String> {
+    /// Checks the groups of a format string can be found, `context`
+    /// starts every message.
+    fn check_groups(&self, format: &str, caps: &[CapGroup<'_>], regex: &dyn Matcher, context: &str) -> Result<(),Cow<'static,str>> {
+        // the other syntaxes have escapes of their own, `\1` is a group rather than octal
+        let native = !self.literal_match && self.syntax == Syntax::Native;
+        if let Some(escape) = CapGroup::find_byte_escape(format).filter(|_| native) {
+            return Err(Cow::from(format!("{}'{}' is a byte above 0x7F, which UTF-8 output can't write on its own, write the character as '\\u{{..}}' instead", context, escape)));
+        }
+        if self.map.is_none() && CapGroup::uses_map(caps) {
+            return Err(Cow::from(format!("{}the format string uses ':map' but no '--map' file was given", context)));
+        }
+        if self.chunk_size.is_some() && CapGroup::uses_count(caps) {
+            return Err(Cow::from(format!("{}'%{{count}}' needs every match before it, it cannot be combined with '--chunked'", context)));
+        }
+        let problems = CapGroup::check_references(caps, regex);
+        if !problems.is_empty() {
+            if !self.lenient {
+                let problems = problems.iter().map(|problem| format!("{}{}", context, problem)).collect::<Vec<_>>();
+                return Err(Cow::from(format!("{}\nsee '--lenient' to treat this as a warning", problems.join("\n"))));
+            }
+            for problem in problems {
+                eprintln!("warning: {}{}", context, problem);
+            }
+        }
+        Ok(())
+    }
+
+    /// the file arguments, starting at `files_at`
+    fn file_args(&self, args: &[String], files_at: usize) -> Result<Vec<String>,Cow<'static,str>> {
+        match self.additional_args_needed() {
+            0 => Ok(Vec::new()),
+            needed => {
+                if args.len() < files_at + needed {
+                    let missing = if needed == 1 { "[FILE]" } else { "[FILE IN] [FILE OUT]" };
+                    return Err(Cow::from(format!("required at least {} args: '{} {}' see '--help' for more info", files_at + needed - 1, args[1..files_at].join(" "), missing)));
+                }
+                // unless redirected, every file given is processed
+                if self.input == Input::File && self.output != Output::DifferentFile {
+                    return Ok(args[files_at..].to_vec());
+                }
+                Ok(args[files_at..files_at+needed].to_vec())
+            }
+        }
+    }
+
+    /// perl idioms are rewritten for whichever engine is used
+    fn translate(&self, arg: &str) -> Result<String,String> {
+        let target = Target {
+            backtracking: self.fancy,
+            lines: !self.matching.is_multi_line(),
+            unicode: !self.ascii_only,
+            extended: self.ignore_whitespace,
+        };
+        translate(arg, target).map_err(|e| e.to_string())
+    }
+
+    /// The first column of every row is a regex, they're
+    /// built with the same flags as `[REGEX]`.
+    fn build_rules(&self, pairs: &[(String,String)]) -> Result<Rules,String> {
+        let mut patterns = Vec::with_capacity(pairs.len());
+        let mut regexes = Vec::with_capacity(pairs.len());
+        for (pos, (pattern, _)) in pairs.iter().enumerate() {
+            let pattern = self.translate(pattern).map_err(|e| format!("rule {}: {}", pos + 1, e))?;
+            let regex = RegexBuilder::new(&pattern)
+                .case_insensitive(self.case_in_sensitive)
+                .swap_greed(self.swap_greedy)
+                .ignore_whitespace(self.ignore_whitespace)
+                .unicode(!self.ascii_only)
+                .build()
+                .map_err(|e| format!("rule {}: {:?}", pos + 1, e))?;
+            patterns.push(pattern);
+            regexes.push(regex);
+        }
+        if regexes.is_empty() {
+            return Err("the '--rules' file has no rules".to_string());
+        }
+        let set = RegexSetBuilder::new(&patterns)
+            .case_insensitive(self.case_in_sensitive)
+            .swap_greed(self.swap_greedy)
+            .ignore_whitespace(self.ignore_whitespace)
+            .unicode(!self.ascii_only)
+            .dfa_size_limit(SET_DFA_SIZE_LIMIT)
+            .build()
+            .map_err(|e| format!("{:?}", e))?;
+        Ok(Rules::new(set, regexes))
+    }
+
+    fn build_regex(&self, arg: &str) -> Result<Box<dyn Matcher>,String> {
         if self.literal_match {
-            Regex::new(&regex::escape(arg)).map_err(|e| format!("{:?}", e))
+            return Ok(Box::new(Literal::new(arg)));
+        }
+        let arg = self.translate(arg)?;
+        if self.fancy {
+            self.build_fancy(&arg)
         } else {
-            RegexBuilder::new(arg)
+            RegexBuilder::new(&arg)
                 .case_insensitive(self.case_in_sensitive)
                 .multi_line(self.matching.is_multi_line())    
                 .dot_matches_new_line(self.matching.is_multi_line() && self.dot_matches_newline)
@@ -130,10 +346,49 @@ impl InitialFlagOptions {
                 .ignore_whitespace(self.ignore_whitespace)
                 .unicode(!self.ascii_only)
                 .build()
-                .map_err(|e| format!("{:?}", e))
+                .map(|regex| Box::new(regex) as Box<dyn Matcher>)
+                .map_err(|e| {
+                    let hint = match e {
+                        regex::Error::Syntax(ref msg) if msg.contains("look-around") || msg.contains("backreferences") => {
+                            if cfg!(feature = "fancy") {
+                                "\nsee '--fancy' for lookaround and backreferences"
+                            } else {
+                                "\nlookaround and backreferences need '--fancy', which requires building sse with '--features fancy'"
+                            }
+                        }
+                        _ => "",
+                    };
+                    format!("{:?}{}", e, hint)
+                })
         }
     }
 
+    /// the flags are set inline, the backtracking engine has no builder for them
+    #[cfg(feature = "fancy")]
+    fn build_fancy(&self, arg: &str) -> Result<Box<dyn Matcher>,String> {
+        let mut flags = String::new();
+        for &(set, flag) in &[
+            (self.case_in_sensitive, 'i'),
+            (self.matching.is_multi_line(), 'm'),
+            (self.matching.is_multi_line() && self.dot_matches_newline, 's'),
+            (self.swap_greedy, 'U'),
+            (self.ignore_whitespace, 'x'),
+        ] {
+            if set {
+                flags.push(flag);
+            }
+        }
+        let pattern = if flags.is_empty() { Cow::Borrowed(arg) } else { Cow::Owned(format!("(?{}){}", flags, arg)) };
+        fancy_regex::Regex::new(&pattern)
+            .map(|regex| Box::new(Fancy::new(regex)) as Box<dyn Matcher>)
+            .map_err(|e| e.to_string())
+    }
+
+    #[cfg(not(feature = "fancy"))]
+    fn build_fancy(&self, _: &str) -> Result<Box<dyn Matcher>,String> {
+        Err("'--fancy' isn't available, sse was built without the 'fancy' feature".to_string())
+    }
+
     fn new(cap: &Captures<'_>) -> Self {
         Self {
             input: Input::new(cap),
@@ -146,9 +401,262 @@ impl InitialFlagOptions {
             swap_greedy: cap.name("SwapGreedy").is_some(),
             dot_matches_newline: cap.name("DotMatchesNewLine").is_some(),
             ascii_only: cap.name("ascii").is_some(),
+            delimited: None,
+            fields: String::new(),
+            header: false,
+            json: None,
+            json_keys: false,
+            columns: None,
+            delimiter: ',',
+            pass_non_numeric: false,
+            lenient: false,
+            syntax: Syntax::Native,
+            regex_file: None,
+            format_file: None,
+            map: None,
+            map_strict: false,
+            literals: None,
+            whole_words: false,
+            fancy: false,
+            rules: None,
+            threads: 0,
+            chunk_size: None,
+            mmap: true,
         }
     }
 
+    /// rejects combinations of options that don't make sense together
+    fn validate(&self) -> Result<(),String> {
+        if self.delimited.is_some() {
+            if self.json.is_some() {
+                return Err("'--json' cannot be combined with '--csv' or '--tsv'".to_string());
+            }
+            if self.columns.is_some() {
+                return Err("'--columns' cannot be combined with '--csv' or '--tsv'".to_string());
+            }
+            if self.nice {
+                return Err("`n` cannot be combined with '--csv' or '--tsv'".to_string());
+            }
+        } else if self.header {
+            return Err("'--header' requires '--csv' or '--tsv'".to_string());
+        }
+        if self.format_file.is_some() && self.delimited.is_some() {
+            return Err("'--format-file' cannot be combined with '--csv' or '--tsv', they don't take a format string".to_string());
+        }
+        let stdin_readers = self.input.is_stdin() as usize
+            + (self.regex_file.as_deref() == Some("-")) as usize
+            + (self.format_file.as_deref() == Some("-")) as usize;
+        if stdin_readers > 1 {
+            return Err("only one of the input (`i`), '--regex-file=-' and '--format-file=-' can read stdin".to_string());
+        }
+        if self.literals.is_some() {
+            let other = if self.delimited.is_some() {
+                Some("'--csv' or '--tsv'")
+            } else if self.json.is_some() {
+                Some("'--json'")
+            } else if self.columns.is_some() {
+                Some("'--columns'")
+            } else if self.regex_file.is_some() {
+                Some("'--regex-file'")
+            } else if self.format_file.is_some() {
+                Some("'--format-file'")
+            } else if self.map.is_some() {
+                Some("'--map'")
+            } else {
+                None
+            };
+            if let Some(other) = other {
+                return Err(format!("'--literals' cannot be combined with {}", other));
+            }
+        } else if self.whole_words {
+            return Err("'--whole-words' requires '--literals'".to_string());
+        }
+        if self.fancy {
+            if self.literal_match {
+                return Err("'--fancy' cannot be combined with `F`, a literal match doesn't need it".to_string());
+            }
+            if self.literals.is_some() {
+                return Err("'--fancy' cannot be combined with '--literals'".to_string());
+            }
+            if self.ascii_only {
+                return Err("'--fancy' cannot be combined with `a`, it always matches unicode".to_string());
+            }
+        }
+        if self.rules.is_some() {
+            let other = if self.delimited.is_some() {
+                Some("'--csv' or '--tsv'")
+            } else if self.json.is_some() {
+                Some("'--json'")
+            } else if self.columns.is_some() {
+                Some("'--columns'")
+            } else if self.regex_file.is_some() {
+                Some("'--regex-file'")
+            } else if self.format_file.is_some() {
+                Some("'--format-file'")
+            } else if self.literals.is_some() {
+                Some("'--literals'")
+            } else if self.fancy {
+                Some("'--fancy'")
+            } else if self.literal_match {
+                Some("`F`")
+            } else {
+                None
+            };
+            if let Some(other) = other {
+                return Err(format!("'--rules' cannot be combined with {}", other));
+            }
+            if self.matching.is_multi_line() {
+                return Err("'--rules' works line-by-line, it cannot be combined with `c`".to_string());
+            }
+        }
+        if self.chunk_size.is_some() {
+            if self.matching.is_multi_line() {
+                return Err("'--chunked' works line-by-line, it cannot be combined with `c`".to_string());
+            }
+            if self.json.is_some() {
+                return Err("'--chunked' cannot be combined with '--json'".to_string());
+            }
+        }
+        if self.map_strict && self.map.is_none() {
+            return Err("'--map-strict' requires '--map'".to_string());
+        }
+        if self.json_keys && self.json.is_none() {
+            return Err("'--json-keys' requires '--json'".to_string());
+        }
+        if self.columns.is_some() {
+            if self.json.is_some() {
+                return Err("'--json' cannot be combined with '--columns'".to_string());
+            }
+            if self.matching.is_multi_line() {
+                return Err("'--columns' works line-by-line, it cannot be combined with `c`".to_string());
+            }
+        }
+        Ok(())
+    }
+
+    /// handles a single `--name[=value]` argument
+    fn long_option(&mut self, cap: &Captures<'_>) -> Result<(),String> {
+        let name = cap.name("name").map(|m| m.as_str()).unwrap_or("");
+        let value = cap.name("value").map(|m| m.as_str());
+        match (name, value) {
+            ("csv", v) |
+            ("tsv", v) => {
+                if self.delimited.is_some() {
+                    return Err("only one of '--csv' or '--tsv' may be given".to_string());
+                }
+                self.delimited = Some(if name == "csv" { Quoting::Csv } else { Quoting::Tsv });
+                self.fields = v.unwrap_or("").to_string();
+            }
+            ("header", Option::None) => {
+                self.header = true;
+            }
+            ("json", Option::Some(v)) => {
+                self.json = Some(JsonPath::parse(v)?);
+            }
+            ("json", Option::None) => {
+                return Err("'--json' requires a path, e.g. '--json=$.servers[*].host'".to_string());
+            }
+            ("json-keys", Option::None) => {
+                self.json_keys = true;
+            }
+            ("columns", Option::Some(v)) => {
+                let mut columns = Vec::new();
+                for item in v.split(',').map(|s| s.trim()) {
+                    match item.parse::<usize>() {
+                        Ok(x) if x > 0 => columns.push(x),
+                        _ => return Err(format!("'--columns' expects a list of column numbers starting at 1, found '{}'", item)),
+                    };
+                }
+                self.columns = Some(columns);
+            }
+            ("delimiter", Option::Some(v)) => {
+                let mut chars = v.chars();
+                self.delimiter = match (v, chars.next(), chars.next()) {
+                    ("tab", _, _) |
+                    ("\\t", _, _) => '\t',
+                    (_, Option::Some(c), Option::None) => c,
+                    _ => return Err(format!("'--delimiter' expects a single character or 'tab', found '{}'", v)),
+                };
+            }
+            ("pass-non-numeric", Option::None) => {
+                self.pass_non_numeric = true;
+            }
+            ("lenient", Option::None) => {
+                self.lenient = true;
+            }
+            ("regex-file", Option::Some(v)) => {
+                self.regex_file = Some(v.to_string());
+            }
+            ("format-file", Option::Some(v)) => {
+                self.format_file = Some(v.to_string());
+            }
+            ("map", Option::Some(v)) => {
+                self.map = Some(v.to_string());
+            }
+            ("map-strict", Option::None) => {
+                self.map_strict = true;
+            }
+            ("literals", Option::Some(v)) => {
+                self.literals = Some(v.to_string());
+            }
+            ("rules", Option::Some(v)) => {
+                self.rules = Some(v.to_string());
+            }
+            ("whole-words", Option::None) => {
+                self.whole_words = true;
+            }
+            ("no-mmap", Option::None) => {
+                self.mmap = false;
+            }
+            ("fancy", Option::None) => {
+                if cfg!(not(feature = "fancy")) {
+                    return Err("'--fancy' isn't available, sse was built without the 'fancy' feature".to_string());
+                }
+                self.fancy = true;
+            }
+            ("threads", Option::Some(v)) => {
+                self.threads = v.parse::<usize>()
+                    .map_err(|_| format!("'--threads' expects a number of threads, 0 for one per core, found '{}'", v))?;
+            }
+            ("chunked", v) => {
+                self.chunk_size = Some(match v {
+                    Option::None => DEFAULT_CHUNK_SIZE,
+                    Option::Some(v) => parse_size(v)
+                        .ok_or_else(|| format!("'--chunked' expects a size such as '4M', '512K' or '65536', found '{}'", v))?,
+                });
+            }
+            ("syntax", Option::Some(v)) => {
+                self.syntax = Syntax::from_name(v)
+                    .ok_or_else(|| format!("unknown syntax '{}', expected one of 'native', 'sed', 'perl', 'python' or 'js'", v))?;
+            }
+            ("columns", Option::None) |
+            ("delimiter", Option::None) |
+            ("syntax", Option::None) |
+            ("regex-file", Option::None) |
+            ("format-file", Option::None) |
+            ("map", Option::None) |
+            ("literals", Option::None) |
+            ("rules", Option::None) |
+            ("threads", Option::None) => {
+                return Err(format!("'--{}' requires a value", name));
+            }
+            ("header", Option::Some(_)) |
+            ("json-keys", Option::Some(_)) |
+            ("pass-non-numeric", Option::Some(_)) |
+            ("lenient", Option::Some(_)) |
+            ("map-strict", Option::Some(_)) |
+            ("whole-words", Option::Some(_)) |
+            ("fancy", Option::Some(_)) |
+            ("no-mmap", Option::Some(_)) => {
+                return Err(format!("'--{}' does not take a value", name));
+            }
+            _ => {
+                return Err(format!("unknown option '--{}' see '--help' for more info", name));
+            }
+        };
+        Ok(())
+    }
+
     #[cfg(test)]
     const fn default() -> Self {
         Self {
@@ -162,6 +670,27 @@ impl InitialFlagOptions {
             swap_greedy: false,
             dot_matches_newline: false,
             ascii_only: false,
+            delimited: None,
+            fields: String::new(),
+            header: false,
+            json: None,
+            json_keys: false,
+            columns: None,
+            delimiter: ',',
+            pass_non_numeric: false,
+            lenient: false,
+            syntax: Syntax::Native,
+            regex_file: None,
+            format_file: None,
+            map: None,
+            map_strict: false,
+            literals: None,
+            whole_words: false,
+            fancy: false,
+            rules: None,
+            threads: 0,
+            chunk_size: None,
+            mmap: true,
         }
     }
 
@@ -219,12 +748,14 @@ impl InitialFlagOptions {
 
 #[test]
 fn test_args() {
-    const DUT: &'static [(&'static str, InitialFlagOptions)] = &[
+    const DUT: &[(&str, InitialFlagOptions)] = &[
         ("-i",InitialFlagOptions::default()),
         ("-in",InitialFlagOptions::default().set_nice(true)),
         ("-fnlo",InitialFlagOptions::default().set_input(Input::File).set_nice(true)),
         ("-fnlwo",InitialFlagOptions::default().set_nice(true).set_input(Input::File).set_matching(Matching::LineByLine(Eol::Windows))),
         ("-flf", InitialFlagOptions::default().set_input(Input::File).set_output(Output::SameFile)),
+        ("-flqo", InitialFlagOptions::default().set_input(Input::File).set_matching(Matching::LineByLine(Eol::Qnx))),
+        ("-fnlaf", InitialFlagOptions::default().set_nice(true).set_input(Input::File).set_matching(Matching::LineByLine(Eol::Acorn)).set_output(Output::SameFile)),
         ("-iFnSxGsacr",InitialFlagOptions::default()
             .set_literal_match(true)
             .set_nice(true)
@@ -273,6 +804,19 @@ fn test_args() {
     }
 }
 
+#[test]
+fn test_byte_escapes() {
+    let regex = Regex::new("a").unwrap();
+    let mut opts = InitialFlagOptions::default();
+    assert!(opts.check_groups(r"\xE9", &[], &regex, "").is_err());
+    assert!(opts.check_groups(r"\200", &[], &regex, "").is_err());
+    // left as written in the other syntaxes, in `sed` `\200` is group 2 and `00`
+    for &syntax in &[Syntax::Sed, Syntax::Perl, Syntax::Python, Syntax::Js] {
+        opts.syntax = syntax;
+        assert!(opts.check_groups(r"\xE9\200", &[], &regex, "").is_ok());
+    }
+}
+
 
 #[allow(dead_code)]
 #[derive(PartialEq,Eq,PartialOrd,Ord,Debug,Clone,Copy)]
@@ -282,13 +826,11 @@ pub enum Matching {
 }
 impl Matching {
 
-    pub fn build_input_stream<R: Read>(&self, input: BufReader<R>) -> Result<(BufferedReader<R>,&'static [u8]), BufReader<R>> {
+    /// The line terminator, continuous mode has none.
+    pub fn term(&self) -> Option<&'static [u8]> {
         match self {
-            &Self::Continuous => Err(input),
-            &Self::LineByLine(ref eol) => {
-                let term = eol.get_eol_bytes();
-                Ok((BufferedReader::new(input, term), term))
-            }
+            Self::Continuous => None,
+            Self::LineByLine(eol) => Some(eol.get_eol_bytes()),
         }
     }
 
@@ -299,9 +841,9 @@ impl Matching {
     fn new(cap: &Captures<'_>) -> Self {
         cap.name("Continuous")
             .is_some()
-            .then(|| Self::Continuous)
+            .then_some(Self::Continuous)
             .or_else(|| cap.name("LineByLine").is_some().then(|| Self::LineByLine(Eol::new(cap))))
-            .unwrap_or_else(|| Self::LineByLine(Eol::Unix))
+            .unwrap_or(Self::LineByLine(Eol::Unix))
     }
 }
 
@@ -318,32 +860,32 @@ pub enum Eol {
 impl Eol {
 
     fn get_eol_bytes(&self) -> &'static [u8] {
-        const WINDOWS_EOL: &'static [u8] = &[ 0x0D, 0x0A];
-        const MAC_EOL: &'static [u8] = &[0x0D];
-        const UNIX_EOL: &'static [u8] = &[0x0A];
-        const IBM_EOL: &'static [u8] = &[0x15];
-        const QNX_EOL: &'static [u8] = &[0x1E];
-        const ACORN_EOL: &'static [u8] = &[0x0A,0x0D];
-        match self {
-            &Self::Windows => WINDOWS_EOL,
-            &Self::Mac => MAC_EOL,
-            &Self::Unix => UNIX_EOL,
-            &Self::Ibm => IBM_EOL,
-            &Self::Qnx => QNX_EOL,
-            &Self::Acorn => ACORN_EOL,
+        const WINDOWS_EOL: &[u8] = &[ 0x0D, 0x0A];
+        const MAC_EOL: &[u8] = &[0x0D];
+        const UNIX_EOL: &[u8] = &[0x0A];
+        const IBM_EOL: &[u8] = &[0x15];
+        const QNX_EOL: &[u8] = &[0x1E];
+        const ACORN_EOL: &[u8] = &[0x0A,0x0D];
+        match *self {
+            Self::Windows => WINDOWS_EOL,
+            Self::Mac => MAC_EOL,
+            Self::Unix => UNIX_EOL,
+            Self::Ibm => IBM_EOL,
+            Self::Qnx => QNX_EOL,
+            Self::Acorn => ACORN_EOL,
         }
     }
 
     fn new(cap: &Captures<'_>) -> Self {
         cap.name("WindowsEoL")
             .is_some()
-            .then(|| Self::Windows)
-            .or_else(|| cap.name("MacEoL").is_some().then(|| Self::Mac))
-            .or_else(|| cap.name("UnixEoL").is_some().then(|| Self::Unix))
-            .or_else(|| cap.name("IBM").is_some().then(|| Self::Ibm))
-            .or_else(|| cap.name("QNX").is_some().then(|| Self::Qnx))
-            .or_else(|| cap.name("Acorn").is_some().then(|| Self::Acorn))
-            .unwrap_or_else(|| Self::Unix)
+            .then_some(Self::Windows)
+            .or_else(|| cap.name("MacEoL").is_some().then_some(Self::Mac))
+            .or_else(|| cap.name("UnixEoL").is_some().then_some(Self::Unix))
+            .or_else(|| cap.name("IBM").is_some().then_some(Self::Ibm))
+            .or_else(|| cap.name("QNX").is_some().then_some(Self::Qnx))
+            .or_else(|| cap.name("Acorn").is_some().then_some(Self::Acorn))
+            .unwrap_or(Self::Unix)
     }
 }
 
@@ -363,15 +905,18 @@ impl Output {
         input_is_stdin: bool,
         args: &[String],
     ) -> Result<BufWriter<Box<dyn MyTrait>>,io::Error> {
-        match self {
-            &Self::Stdout => Ok(BufWriter::with_capacity(16 * 1024, Box::new(std::io::stdout()))),
-            &Self::Stderr => Ok(BufWriter::with_capacity(16 * 1024, Box::new(std::io::stderr()))),
-            &Self::SameFile |
-            &Self::DifferentFile => {
+        match *self {
+            Self::Stdout => Ok(BufWriter::with_capacity(16 * 1024, Box::new(std::io::stdout()))),
+            Self::Stderr => Ok(BufWriter::with_capacity(16 * 1024, Box::new(std::io::stderr()))),
+            Self::SameFile => {
+                // the input file when writing back, otherwise the only file
+                Ok(BufWriter::with_capacity(16 * 1024, Box::new(std::fs::OpenOptions::new().write(true).create(true).truncate(true).open(&args[0])?)))
+            },
+            Self::DifferentFile => {
                 if input_is_stdin {
-                    Ok(BufWriter::with_capacity(16 * 1024, Box::new(std::fs::OpenOptions::new().create(true).truncate(true).open(&args[0])?)))
+                    Ok(BufWriter::with_capacity(16 * 1024, Box::new(std::fs::OpenOptions::new().write(true).create(true).truncate(true).open(&args[0])?)))
                 } else {
-                    Ok(BufWriter::with_capacity(16 * 1024, Box::new(std::fs::OpenOptions::new().create(true).truncate(true).open(&args[1])?)))
+                    Ok(BufWriter::with_capacity(16 * 1024, Box::new(std::fs::OpenOptions::new().write(true).create(true).truncate(true).open(&args[1])?)))
                 }
             },
         }
@@ -382,22 +927,22 @@ impl Output {
         input_is_stdin: bool,
         args: &[String],
     ) -> Result<Option<BufWriter<Box<dyn MyTrait>>>,io::Error> {
-        match self {
-            &Self::Stdout => Ok(Some(BufWriter::with_capacity(16 * 1024, Box::new(std::io::stdout())))),
-            &Self::Stderr => Ok(Some(BufWriter::with_capacity(16 * 1024, Box::new(std::io::stderr())))),
-            &Self::SameFile => {
+        match *self {
+            Self::Stdout => Ok(Some(BufWriter::with_capacity(16 * 1024, Box::new(std::io::stdout())))),
+            Self::Stderr => Ok(Some(BufWriter::with_capacity(16 * 1024, Box::new(std::io::stderr())))),
+            Self::SameFile => {
                 if input_is_stdin {
-                    Ok(Some(BufWriter::with_capacity(16 * 1024, Box::new(std::fs::OpenOptions::new().create(true).truncate(true).open(&args[0])?))))
+                    Ok(Some(BufWriter::with_capacity(16 * 1024, Box::new(std::fs::OpenOptions::new().write(true).create(true).truncate(true).open(&args[0])?))))
                 } else {
                     // writing back to same file
                     Ok(None)
                 }
             },
-            &Self::DifferentFile => {
+            Self::DifferentFile => {
                 if input_is_stdin {
-                    Ok(Some(BufWriter::with_capacity(16 * 1024, Box::new(std::fs::OpenOptions::new().create(true).truncate(true).open(&args[0])?))))
+                    Ok(Some(BufWriter::with_capacity(16 * 1024, Box::new(std::fs::OpenOptions::new().write(true).create(true).truncate(true).open(&args[0])?))))
                 } else {
-                    Ok(Some(BufWriter::with_capacity(16 * 1024, Box::new(std::fs::OpenOptions::new().create(true).truncate(true).open(&args[1])?))))
+                    Ok(Some(BufWriter::with_capacity(16 * 1024, Box::new(std::fs::OpenOptions::new().write(true).create(true).truncate(true).open(&args[1])?))))
                 }
             },
         }
@@ -407,12 +952,12 @@ impl Output {
         cap.name("output")
             .is_some()
             .then(||
-                cap.name("stdout").is_some().then(|| Output::Stdout)
-                    .or_else(|| cap.name("stderr").is_some().then(|| Output::Stderr))
-                    .or_else(|| cap.name("writeback").is_some().then(|| Output::SameFile))
-                    .or_else(|| cap.name("redirect").is_some().then(|| Output::DifferentFile)))
+                cap.name("stdout").is_some().then_some(Output::Stdout)
+                    .or_else(|| cap.name("stderr").is_some().then_some(Output::Stderr))
+                    .or_else(|| cap.name("writeback").is_some().then_some(Output::SameFile))
+                    .or_else(|| cap.name("redirect").is_some().then_some(Output::DifferentFile)))
             .flatten()
-            .unwrap_or_else(|| Output::Stdout)
+            .unwrap_or(Output::Stdout)
     }
 }
 
@@ -428,10 +973,18 @@ impl Input {
         *self == Self::Stdin
     }
 
-    pub fn open_input(&self, opts: &[String]) -> Result<BufReader<Box<dyn Read>>,io::Error> {
+    /// the input file as given, `-` for stdin
+    pub fn path<'s>(&self, opts: &'s [String]) -> &'s str {
         match self {
-            &Self::Stdin => Ok(BufReader::with_capacity(32 * 1024, Box::new(std::io::stdin()))),
-            &Self::File => Ok(BufReader::with_capacity(32 * 1024, Box::new(std::fs::File::open(&opts[0])?))),
+            Self::Stdin => "-",
+            Self::File => &opts[0],
+        }
+    }
+
+    pub fn open_input(&self, opts: &[String]) -> Result<BufReader<Box<dyn Read>>,io::Error> {
+        match *self {
+            Self::Stdin => Ok(BufReader::with_capacity(32 * 1024, Box::new(std::io::stdin()))),
+            Self::File => Ok(BufReader::with_capacity(32 * 1024, Box::new(std::fs::File::open(&opts[0])?))),
         }
     }
 
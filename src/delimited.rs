@@ -0,0 +1,286 @@
+
+use std::borrow::Cow;
+
+use crate::{
+    cap_groups::CapGroup,
+    matcher::Matcher,
+};
+
+/// How a field is escaped when written as part of a delimited row.
+#[derive(Clone,Copy,PartialEq,Eq,PartialOrd,Ord,Hash,Debug)]
+pub enum Quoting {
+    /// RFC 4180 style, fields containing `,` `"` or a line break
+    /// are wrapped in `"` and embedded `"` are doubled.
+    Csv,
+    /// Tab separated, tabs, line breaks and `\` within a field are
+    /// written as `\t` `\n` `\r` and `\\`.
+    Tsv,
+}
+impl Quoting {
+
+    pub fn separator(&self) -> char {
+        match *self {
+            Self::Csv => ',',
+            Self::Tsv => '\t',
+        }
+    }
+
+    pub fn quote(&self, field: &str, buffer: &mut String) {
+        match *self {
+            Self::Csv => quote_field(field, ',', buffer),
+            Self::Tsv => {
+                for c in field.chars() {
+                    match c {
+                        '\t' => buffer.push_str("\\t"),
+                        '\n' => buffer.push_str("\\n"),
+                        '\r' => buffer.push_str("\\r"),
+                        '\\' => buffer.push_str("\\\\"),
+                        x => buffer.push(x),
+                    };
+                }
+            }
+        }
+    }
+}
+
+/// Writes `field` for a CSV style record separated by `delimiter`. It is
+/// only wrapped in `"` when it contains the delimiter, a `"` or a line break.
+pub fn quote_field(field: &str, delimiter: char, buffer: &mut String) {
+    if field.contains([delimiter, '"', '\n', '\r']) {
+        buffer.push('"');
+        for c in field.chars() {
+            if c == '"' {
+                buffer.push('"');
+            }
+            buffer.push(c);
+        }
+        buffer.push('"');
+    } else {
+        buffer.push_str(field);
+    }
+}
+
+/// Splits a CSV style record on `delimiter`, returning the raw text and
+/// the unquoted value of every field. Parsing is lenient, text after a
+/// closing quote is kept and an unclosed quote runs to the end of `line`.
+pub fn split_record(line: &str, delimiter: char) -> Vec<(&str,Cow<'_,str>)> {
+    let mut fields = Vec::new();
+    let mut start = 0usize;
+    loop {
+        let rest = &line[start..];
+        let (len, value) = if rest.starts_with('"') {
+            let mut value = String::new();
+            let mut chars = rest.char_indices().skip(1).peekable();
+            let mut in_quotes = true;
+            let mut len = rest.len();
+            while let Some((pos, c)) = chars.next() {
+                if in_quotes && c == '"' {
+                    if let Some(&(_, '"')) = chars.peek() {
+                        chars.next();
+                        value.push('"');
+                    } else {
+                        in_quotes = false;
+                    }
+                } else if !in_quotes && c == delimiter {
+                    len = pos;
+                    break;
+                } else {
+                    value.push(c);
+                }
+            }
+            (len, Cow::Owned(value))
+        } else {
+            let len = rest.find(delimiter).unwrap_or(rest.len());
+            (len, Cow::Borrowed(&rest[..len]))
+        };
+        fields.push((&rest[..len], value));
+        start += len;
+        if start >= line.len() {
+            return fields;
+        }
+        start += delimiter.len_utf8();
+        if start == line.len() {
+            // a trailing delimiter ends with an empty field
+            fields.push(("", Cow::Borrowed("")));
+            return fields;
+        }
+    }
+}
+
+/// Maps `offset`, a byte offset into the unquoted value of the field
+/// `raw` (as split by `split_record`), back to one into `raw`.
+pub fn raw_offset(raw: &str, offset: usize) -> usize {
+    if !raw.starts_with('"') {
+        return offset;
+    }
+    let mut value_len = 0usize;
+    let mut chars = raw.char_indices().skip(1).peekable();
+    let mut in_quotes = true;
+    while let Some((pos, c)) = chars.next() {
+        if value_len >= offset {
+            return pos;
+        }
+        if in_quotes && c == '"' {
+            if let Some(&(_, '"')) = chars.peek() {
+                chars.next();
+                value_len += 1;
+            } else {
+                in_quotes = false;
+            }
+        } else {
+            value_len += c.len_utf8();
+        }
+    }
+    raw.len()
+}
+
+/// Builds the template used to write one row per match, and the
+/// header row made up of the selected group's names. The header is
+/// returned without a record terminator.
+///
+/// `spec` is a comma separated list of group numbers and names,
+/// when it is empty every capture group (except `0`) is selected.
+pub fn build_row<'a>(
+    spec: &'a str,
+    quoting: Quoting,
+    regex: &dyn Matcher,
+    record_end: Option<char>,
+) -> Result<(Vec<CapGroup<'a>>,String),String> {
+    let mut fields: Vec<CapGroup<'a>> = Vec::new();
+    if spec.trim().is_empty() {
+        for x in 1..regex.captures_len() {
+            fields.push(field_for_index(x));
+        }
+    } else {
+        for item in spec.split(',').map(|s| s.trim()) {
+            if item.is_empty() {
+                return Err(format!("empty field in selection '{}'", spec));
+            }
+            if item.bytes().all(|b| b.is_ascii_digit()) {
+                let x = item.parse::<usize>().map_err(|e| format!("field '{}': {:?}", item, e))?;
+                if x >= regex.captures_len() {
+                    return Err(format!("field '{}' is out of range, the regex has {} capture groups", item, regex.captures_len() - 1));
+                }
+                fields.push(field_for_index(x));
+            } else {
+                if regex.group_index(item).is_none() {
+                    return Err(format!("the regex has no capture group named '{}'", item));
+                }
+                fields.push(CapGroup::Labelled(item));
+            }
+        }
+    }
+    if fields.is_empty() {
+        return Err("the regex has no capture groups to output".to_string());
+    }
+
+    let mut header = String::new();
+    let mut row = Vec::with_capacity(fields.len() * 2 + 1);
+    for (pos, field) in fields.into_iter().enumerate() {
+        if pos != 0 {
+            header.push(quoting.separator());
+            row.push(CapGroup::SingleChar(quoting.separator()));
+        }
+        match &field {
+            &CapGroup::SingleDigit(x) |
+            &CapGroup::MultiDigit(x) => {
+                let name = regex.group_names()
+                    .get(x)
+                    .and_then(|name| *name)
+                    .map(|name| name.to_string())
+                    .unwrap_or_else(|| x.to_string());
+                quoting.quote(&name, &mut header);
+            }
+            &CapGroup::Labelled(name) => quoting.quote(name, &mut header),
+            _ => { }
+        };
+        row.push(CapGroup::Quoted(Box::new(field), quoting));
+    }
+    if let Some(c) = record_end {
+        row.push(CapGroup::SingleChar(c));
+    }
+    Ok((row,header))
+}
+
+fn field_for_index<'a>(x: usize) -> CapGroup<'a> {
+    if x < 10 {
+        CapGroup::SingleDigit(x)
+    } else {
+        CapGroup::MultiDigit(x)
+    }
+}
+
+#[test]
+fn test_csv_quoting() {
+    let mut s = String::new();
+    Quoting::Csv.quote("plain", &mut s);
+    assert_eq!(s, "plain");
+    s.clear();
+    Quoting::Csv.quote("a,b", &mut s);
+    assert_eq!(s, "\"a,b\"");
+    s.clear();
+    Quoting::Csv.quote("say \"hi\"", &mut s);
+    assert_eq!(s, "\"say \"\"hi\"\"\"");
+    s.clear();
+    Quoting::Csv.quote("two\nlines", &mut s);
+    assert_eq!(s, "\"two\nlines\"");
+    s.clear();
+    Quoting::Tsv.quote("a\tb\\c\nd", &mut s);
+    assert_eq!(s, "a\\tb\\\\c\\nd");
+}
+
+#[test]
+fn test_split_record() {
+    let values = |line: &'static str, delimiter: char| {
+        split_record(line, delimiter)
+            .into_iter()
+            .map(|(raw, value)| (raw, value.into_owned()))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(values("a,b,,c", ','), vec![("a", "a".to_string()), ("b", "b".to_string()), ("", "".to_string()), ("c", "c".to_string())]);
+    assert_eq!(values("\"x,y\",\"say \"\"hi\"\"\",", ','), vec![("\"x,y\"", "x,y".to_string()), ("\"say \"\"hi\"\"\"", "say \"hi\"".to_string()), ("", "".to_string())]);
+    assert_eq!(values("1\t\"2\"\t3", '\t'), vec![("1", "1".to_string()), ("\"2\"", "2".to_string()), ("3", "3".to_string())]);
+    assert_eq!(values("", ','), vec![("", "".to_string())]);
+    assert_eq!(values("\"open,end", ','), vec![("\"open,end", "open,end".to_string())]);
+
+    let mut s = String::new();
+    quote_field("a;b", ';', &mut s);
+    assert_eq!(s, "\"a;b\"");
+}
+
+#[test]
+fn test_raw_offset() {
+    let line = "plain,\"say \"\"hi\"\" there\",\"\"";
+    let fields = split_record(line, ',');
+    assert_eq!(fields[1].1, "say \"hi\" there");
+    for &(offset, expected) in &[(0, 1), (4, 5), (5, 7), (6, 8), (7, 9), (8, 11), (9, 12), (14, 17)] {
+        assert_eq!(raw_offset(fields[1].0, offset), expected, "{}", offset);
+    }
+    assert_eq!(raw_offset(fields[0].0, 3), 3);
+    assert_eq!(raw_offset(fields[2].0, 0), 1);
+}
+
+#[test]
+fn test_build_row() {
+    use crate::cap_groups::Context;
+
+    let regex = regex::Regex::new(r#"(?P<date>\d+-\d+-\d+) (?P<level>\w+)(\s(\w+))?"#).unwrap();
+    let (row, header) = build_row("level,1,4", Quoting::Csv, &regex, Some('\n')).unwrap();
+    assert_eq!(header, "level,date,4");
+
+    let caps = regex.captures_from("2022-01-02 WARN", 0).unwrap();
+    let mut s = String::new();
+    CapGroup::output(&row, &caps, &Context::default(), &mut s).unwrap();
+    assert_eq!(s, "WARN,2022-01-02,\n");
+
+    let (row, header) = build_row("", Quoting::Tsv, &regex, None).unwrap();
+    assert_eq!(header, "date\tlevel\t3\t4");
+    let caps = regex.captures_from("2022-01-02 WARN\tdisk", 0).unwrap();
+    let mut s = String::new();
+    CapGroup::output(&row, &caps, &Context::default(), &mut s).unwrap();
+    assert_eq!(s, "2022-01-02\tWARN\t\\tdisk\tdisk");
+
+    assert!(build_row("nope", Quoting::Csv, &regex, None).is_err());
+    assert!(build_row("5", Quoting::Csv, &regex, None).is_err());
+    assert!(build_row("1,,2", Quoting::Csv, &regex, None).is_err());
+}
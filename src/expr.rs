@@ -0,0 +1,532 @@
+
+use regex::Regex;
+use lazy_static::lazy_static;
+
+use crate::{
+    cap_groups::{CapGroup,is_label_byte},
+    matcher,
+};
+
+lazy_static! {
+    static ref NUMBER_FORMAT: Regex = Regex::new(r#"^(?P<alternate>#)?(?P<zero>0)?(?P<width>[0-9]+)?(\.(?P<precision>[0-9]+))?(?P<kind>[dxXob])?$"#).unwrap();
+    static ref DECIMAL: Regex = Regex::new(r#"^([0-9]+\.?[0-9]*|\.[0-9]+)([eE][+\-]?[0-9]+)?$"#).unwrap();
+}
+
+/// A value within an expression, integers stay integers until
+/// they're mixed with a decimal or divided unevenly.
+#[derive(Clone,Copy,PartialEq,Debug)]
+pub enum Number {
+    Int(i128),
+    Float(f64),
+}
+impl Number {
+
+    /// Parses decimal integers and decimals, or integers with
+    /// a `0x` `0o` or `0b` prefix. Surrounding whitespace is ignored.
+    pub fn parse(arg: &str) -> Option<Number> {
+        let arg = arg.trim();
+        let (negative, body) = match arg.strip_prefix('-') {
+            Option::Some(rest) => (true, rest),
+            Option::None => (false, arg.strip_prefix('+').unwrap_or(arg)),
+        };
+        let radix = match body.get(..2) {
+            Option::Some("0x") | Option::Some("0X") => 16,
+            Option::Some("0o") | Option::Some("0O") => 8,
+            Option::Some("0b") | Option::Some("0B") => 2,
+            _ => 10,
+        };
+        let value = if radix != 10 {
+            Number::Int(i128::from_str_radix(&body[2..], radix).ok()?)
+        } else if !body.is_empty() && body.bytes().all(|b| b.is_ascii_digit()) {
+            Number::Int(body.parse::<i128>().ok()?)
+        } else if DECIMAL.is_match(body) {
+            Number::Float(body.parse::<f64>().ok()?)
+        } else {
+            return None;
+        };
+        Some(match (negative, value) {
+            (true, Number::Int(x)) => Number::Int(-x),
+            (true, Number::Float(x)) => Number::Float(-x),
+            (false, x) => x,
+        })
+    }
+
+    fn as_f64(&self) -> f64 {
+        match *self {
+            Number::Int(x) => x as f64,
+            Number::Float(x) => x,
+        }
+    }
+
+    fn apply(op: u8, lhs: Number, rhs: Number) -> Result<Number,String> {
+        let overflow = || "arithmetic overflow".to_string();
+        match (lhs, rhs) {
+            (Number::Int(a), Number::Int(b)) => {
+                match op {
+                    b'+' => a.checked_add(b).map(Number::Int).ok_or_else(overflow),
+                    b'-' => a.checked_sub(b).map(Number::Int).ok_or_else(overflow),
+                    b'*' => a.checked_mul(b).map(Number::Int).ok_or_else(overflow),
+                    b'/' if b == 0 => Err("division by zero".to_string()),
+                    b'/' => match a.checked_rem(b) {
+                        Option::Some(0) => a.checked_div(b).map(Number::Int).ok_or_else(overflow),
+                        Option::Some(_) => Ok(Number::Float(a as f64 / b as f64)),
+                        Option::None => Err(overflow()),
+                    },
+                    b'%' if b == 0 => Err("division by zero".to_string()),
+                    _ => a.checked_rem(b).map(Number::Int).ok_or_else(overflow),
+                }
+            }
+            (a, b) => {
+                let (a, b) = (a.as_f64(), b.as_f64());
+                match op {
+                    b'+' => Ok(Number::Float(a + b)),
+                    b'-' => Ok(Number::Float(a - b)),
+                    b'*' => Ok(Number::Float(a * b)),
+                    _ if b == 0.0 => Err("division by zero".to_string()),
+                    b'/' => Ok(Number::Float(a / b)),
+                    _ => Ok(Number::Float(a % b)),
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone,Copy,PartialEq,Eq,PartialOrd,Ord,Hash,Debug)]
+pub enum Radix {
+    Decimal,
+    Hex,
+    UpperHex,
+    Octal,
+    Binary,
+}
+
+/// How the result of a calculation is written,
+/// `[#][0][width][.precision][d|x|X|o|b]`.
+#[derive(Clone,Copy,PartialEq,Eq,PartialOrd,Ord,Hash,Debug)]
+pub struct NumberFormat {
+    pub alternate: bool,
+    pub zero: bool,
+    pub width: usize,
+    pub precision: Option<usize>,
+    pub radix: Radix,
+}
+impl Default for NumberFormat {
+    fn default() -> Self {
+        NumberFormat {
+            alternate: false,
+            zero: false,
+            width: 0,
+            precision: None,
+            radix: Radix::Decimal,
+        }
+    }
+}
+impl NumberFormat {
+
+    pub fn parse(arg: &str) -> Option<NumberFormat> {
+        let cap = NUMBER_FORMAT.captures(arg)?;
+        Some(NumberFormat {
+            alternate: cap.name("alternate").is_some(),
+            zero: cap.name("zero").is_some(),
+            width: match cap.name("width") {
+                Option::None => 0,
+                Option::Some(m) => m.as_str().parse::<usize>().ok()?,
+            },
+            precision: match cap.name("precision") {
+                Option::None => None,
+                Option::Some(m) => Some(m.as_str().parse::<usize>().ok()?),
+            },
+            radix: match cap.name("kind").map(|m| m.as_str()) {
+                Option::Some("x") => Radix::Hex,
+                Option::Some("X") => Radix::UpperHex,
+                Option::Some("o") => Radix::Octal,
+                Option::Some("b") => Radix::Binary,
+                _ => Radix::Decimal,
+            },
+        })
+    }
+
+    pub fn write(&self, value: Number, buffer: &mut String) -> Result<(),String> {
+        let integer = match value {
+            Number::Int(x) => Some(x),
+            Number::Float(x) if x.fract() == 0.0 && x.abs() < 1e38 => Some(x as i128),
+            Number::Float(_) => None,
+        };
+        let (negative, digits) = match (self.radix, self.precision, integer) {
+            (Radix::Decimal, Option::Some(p), _) => {
+                let x = value.as_f64();
+                (x < 0.0, format!("{:.*}", p, x.abs()))
+            }
+            (Radix::Decimal, Option::None, Option::Some(x)) => (x < 0, x.unsigned_abs().to_string()),
+            (Radix::Decimal, Option::None, Option::None) => {
+                let x = value.as_f64();
+                (x < 0.0, x.abs().to_string())
+            }
+            (_, _, Option::None) => return Err(format!("{} has a fraction, it can't be written in hex, octal or binary", value.as_f64())),
+            (radix, _, Option::Some(x)) => {
+                let abs = x.unsigned_abs();
+                (x < 0, match radix {
+                    Radix::Hex => format!("{:x}", abs),
+                    Radix::UpperHex => format!("{:X}", abs),
+                    Radix::Octal => format!("{:o}", abs),
+                    _ => format!("{:b}", abs),
+                })
+            }
+        };
+        let prefix = match (self.alternate, self.radix) {
+            (true, Radix::Hex) | (true, Radix::UpperHex) => "0x",
+            (true, Radix::Octal) => "0o",
+            (true, Radix::Binary) => "0b",
+            _ => "",
+        };
+        let len = negative as usize + prefix.len() + digits.len();
+        let padding = self.width.saturating_sub(len);
+        if !self.zero {
+            buffer.extend(std::iter::repeat(' ').take(padding));
+        }
+        if negative {
+            buffer.push('-');
+        }
+        buffer.push_str(prefix);
+        if self.zero {
+            buffer.extend(std::iter::repeat('0').take(padding));
+        }
+        buffer.push_str(&digits);
+        Ok(())
+    }
+}
+
+/// Why a calculation couldn't produce a number.
+#[derive(Clone,PartialEq,Eq,Debug)]
+pub enum CalcError {
+    /// a capture group didn't hold a number
+    NotANumber(String),
+    /// division by zero, overflow or a format the result doesn't fit
+    Failed(String),
+}
+impl std::fmt::Display for CalcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            &CalcError::NotANumber(ref msg) |
+            &CalcError::Failed(ref msg) => f.write_str(msg),
+        }
+    }
+}
+
+#[derive(Clone,PartialEq,Eq,PartialOrd,Ord,Hash,Debug)]
+pub enum Expr<'a> {
+    /// a capture group, `SingleDigit` `MultiDigit` or `Labelled`
+    Group(CapGroup<'a>),
+    /// a number written in the expression, checked when it is parsed
+    Literal(&'a str),
+    Negate(Box<Expr<'a>>),
+    Binary(u8, Box<Expr<'a>>, Box<Expr<'a>>),
+}
+impl<'a> Expr<'a> {
+
+    fn eval(&self, caps: &matcher::Captures<'_>) -> Result<Number,CalcError> {
+        match self {
+            Expr::Group(group) => {
+                let text = group.capture(caps).map(|m| m.as_str()).unwrap_or("");
+                let name = match group {
+                    CapGroup::Labelled(label) => label.to_string(),
+                    CapGroup::MultiDigit(x) |
+                    CapGroup::SingleDigit(x) => x.to_string(),
+                    _ => String::new(),
+                };
+                Number::parse(text).ok_or_else(|| CalcError::NotANumber(format!("capture group '{}' is not a number: {:?}", name, text)))
+            }
+            Expr::Literal(x) => Number::parse(x).ok_or_else(|| CalcError::Failed(format!("{:?} is not a number", x))),
+            Expr::Negate(inner) => {
+                match inner.eval(caps)? {
+                    Number::Int(x) => x.checked_neg().map(Number::Int).ok_or_else(|| CalcError::Failed("arithmetic overflow".to_string())),
+                    Number::Float(x) => Ok(Number::Float(-x)),
+                }
+            }
+            Expr::Binary(op, lhs, rhs) => Number::apply(*op, lhs.eval(caps)?, rhs.eval(caps)?).map_err(CalcError::Failed),
+        }
+    }
+}
+
+/// `%<1+1>` `%<count*1000:08>` `%<2:#x>`, arithmetic on captured numbers.
+/// The first operand is always a capture group, numbers after it are
+/// literals, other groups are written `%N` or `%<name>`.
+#[derive(Clone,PartialEq,Eq,PartialOrd,Ord,Hash,Debug)]
+pub struct Calculation<'a> {
+    pub source: &'a str,
+    pub expr: Expr<'a>,
+    pub format: NumberFormat,
+}
+impl<'a> Calculation<'a> {
+
+    /// Parses a calculation at the start of `arg`, returning it and the
+    /// number of bytes it takes up. A bare `%<N>` is not a calculation.
+    pub fn parse(arg: &'a str) -> Option<(Calculation<'a>,usize)> {
+        if !arg.starts_with("%<") {
+            return None;
+        }
+        let mut parser = ExprParser { arg, pos: 2, depth: 0 };
+        let first = parser.reference()?;
+        let expr = parser.binary(0, Expr::Group(first))?;
+        parser.skip_ws();
+        let format = if parser.eat(b':') {
+            let end = parser.pos + arg[parser.pos..].find('>')?;
+            let format = NumberFormat::parse(&arg[parser.pos..end])?;
+            parser.pos = end;
+            Some(format)
+        } else {
+            None
+        };
+        if !parser.eat(b'>') {
+            return None;
+        }
+        if format.is_none() && matches!(expr, Expr::Group(_)) {
+            return None;
+        }
+        let calculation = Calculation {
+            source: &arg[..parser.pos],
+            expr,
+            format: format.unwrap_or_default(),
+        };
+        Some((calculation, parser.pos))
+    }
+
+    /// Writes the result, on error nothing is written to `buffer`.
+    pub fn write(&self, caps: &matcher::Captures<'_>, buffer: &mut String) -> Result<(),CalcError> {
+        let value = self.expr.eval(caps).map_err(|e| match e {
+            CalcError::NotANumber(msg) => CalcError::NotANumber(format!("'{}': {}", self.source, msg)),
+            CalcError::Failed(msg) => CalcError::Failed(format!("'{}': {}", self.source, msg)),
+        })?;
+        self.format.write(value, buffer).map_err(|msg| CalcError::Failed(format!("'{}': {}", self.source, msg)))
+    }
+
+    /// every capture group the calculation refers to
+    pub fn groups(&self) -> Vec<&CapGroup<'a>> {
+        fn walk<'e,'a>(expr: &'e Expr<'a>, groups: &mut Vec<&'e CapGroup<'a>>) {
+            match expr {
+                Expr::Group(group) => groups.push(group),
+                Expr::Binary(_, lhs, rhs) => {
+                    walk(lhs, groups);
+                    walk(rhs, groups);
+                }
+                Expr::Negate(inner) => walk(inner, groups),
+                Expr::Literal(_) => { }
+            };
+        }
+        let mut groups = Vec::new();
+        walk(&self.expr, &mut groups);
+        groups
+    }
+
+    /// the text of the first capture group, written instead of the
+    /// result when non numeric captures are passed through
+    pub fn fallback<'t>(&self, caps: &matcher::Captures<'t>) -> &'t str {
+        fn first<'a,'t>(expr: &Expr<'a>, caps: &matcher::Captures<'t>) -> &'t str {
+            match expr {
+                Expr::Group(group) => group.capture(caps).map(|m| m.as_str()).unwrap_or(""),
+                Expr::Binary(_, lhs, _) => first(lhs, caps),
+                Expr::Negate(inner) => first(inner, caps),
+                Expr::Literal(_) => "",
+            }
+        }
+        first(&self.expr, caps)
+    }
+}
+
+/// Parentheses and negations nested deeper than this aren't a calculation,
+/// parsing them would run out of stack.
+const MAX_NESTING: usize = 64;
+
+struct ExprParser<'a> {
+    arg: &'a str,
+    pos: usize,
+    /// the number of parentheses and negations the parser is within
+    depth: usize,
+}
+impl<'a> ExprParser<'a> {
+
+    fn peek(&self) -> Option<u8> {
+        self.arg.as_bytes().get(self.pos).copied()
+    }
+
+    fn skip_ws(&mut self) {
+        while self.peek() == Some(b' ') {
+            self.pos += 1;
+        }
+    }
+
+    fn eat(&mut self, b: u8) -> bool {
+        if self.peek() == Some(b) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn take_while<F: Fn(u8) -> bool>(&mut self, f: F) -> &'a str {
+        let start = self.pos;
+        while self.peek().map(&f).unwrap_or(false) {
+            self.pos += 1;
+        }
+        &self.arg[start..self.pos]
+    }
+
+    /// a group number or label
+    fn reference(&mut self) -> Option<CapGroup<'a>> {
+        match self.peek()? {
+            b'0'..=b'9' => {
+                let digits = self.take_while(|b| b.is_ascii_digit());
+                Some(CapGroup::MultiDigit(digits.parse::<usize>().ok()?))
+            }
+            b if is_label_byte(b, true) => {
+                let label = self.take_while(|b| is_label_byte(b, false));
+                Some(CapGroup::Labelled(label))
+            }
+            _ => None,
+        }
+    }
+
+    fn binary(&mut self, min_precedence: u8, mut lhs: Expr<'a>) -> Option<Expr<'a>> {
+        loop {
+            self.skip_ws();
+            let (op, precedence) = match self.peek() {
+                Option::Some(b'+') | Option::Some(b'-') => (self.peek()?, 1),
+                Option::Some(b'*') | Option::Some(b'/') | Option::Some(b'%') => (self.peek()?, 2),
+                _ => return Some(lhs),
+            };
+            if precedence < min_precedence {
+                return Some(lhs);
+            }
+            self.pos += 1;
+            let mut rhs = self.unary()?;
+            loop {
+                self.skip_ws();
+                let next = match self.peek() {
+                    Option::Some(b'*') | Option::Some(b'/') | Option::Some(b'%') => 2,
+                    _ => break,
+                };
+                if next <= precedence {
+                    break;
+                }
+                rhs = self.binary(next, rhs)?;
+            }
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+    }
+
+    fn unary(&mut self) -> Option<Expr<'a>> {
+        self.skip_ws();
+        if self.peek() == Some(b'-') || self.peek() == Some(b'(') {
+            if self.depth == MAX_NESTING {
+                return None;
+            }
+            self.depth += 1;
+            let expr = self.nested();
+            self.depth -= 1;
+            return expr;
+        }
+        if self.eat(b'%') {
+            if self.eat(b'<') {
+                let group = self.reference()?;
+                return self.eat(b'>').then_some(Expr::Group(group));
+            }
+            let digit = self.take_while(|b| b.is_ascii_digit());
+            if digit.len() != 1 {
+                return None;
+            }
+            return Some(Expr::Group(CapGroup::SingleDigit(digit.parse::<usize>().ok()?)));
+        }
+        let literal = self.take_while(|b| b.is_ascii_alphanumeric() || b == b'.');
+        Number::parse(literal)?;
+        Some(Expr::Literal(literal))
+    }
+
+    /// a negation or an expression in parentheses
+    fn nested(&mut self) -> Option<Expr<'a>> {
+        if self.eat(b'-') {
+            return Some(Expr::Negate(Box::new(self.unary()?)));
+        }
+        self.pos += 1;
+        let lhs = self.unary()?;
+        let expr = self.binary(0, lhs)?;
+        self.skip_ws();
+        self.eat(b')').then_some(expr)
+    }
+}
+
+#[test]
+fn test_number_parse() {
+    assert_eq!(Number::parse("42"), Some(Number::Int(42)));
+    assert_eq!(Number::parse(" -7 "), Some(Number::Int(-7)));
+    assert_eq!(Number::parse("0x1f"), Some(Number::Int(31)));
+    assert_eq!(Number::parse("-0b101"), Some(Number::Int(-5)));
+    assert_eq!(Number::parse("1.5"), Some(Number::Float(1.5)));
+    assert_eq!(Number::parse(".5e1"), Some(Number::Float(5.0)));
+    assert_eq!(Number::parse("abc"), None);
+    assert_eq!(Number::parse("inf"), None);
+    assert_eq!(Number::parse(""), None);
+}
+
+#[test]
+fn test_calculation() {
+    use crate::matcher::Matcher;
+    let regex = Regex::new(r#"(\S+) (?P<count>\S+)"#).unwrap();
+    const DUT: &[(&str, &str, &str)] = &[
+        ("5000", "3 5", "%<2*1000>"),
+        ("7", "3 5", "%<1+%<count>-1>"),
+        ("23", "3 5", "%<1+%2*4>"),
+        ("21", "3 5", "%<1*(2+%<count>)>"),
+        ("-2", "3 5", "%<1 - %2>"),
+        ("2.5", "5 x", "%<1/2>"),
+        ("3.00", "3 x", "%<1*1:.2>"),
+        ("ff", "255 x", "%<1:x>"),
+        ("0xFF", "255 x", "%<1:#X>"),
+        ("0o17", "15 x", "%<1:#o>"),
+        ("00042", "42 x", "%<1+0:05>"),
+        ("-0042", "-42 x", "%<1+0:05>"),
+        ("   42", "42 x", "%<1+0:5>"),
+        ("b", "0x10 x", "%<1-5:x>"),
+        ("1", "7 x", "%<1%2>"),
+    ];
+    for &(expected, input, template) in DUT {
+        let (calc, len) = Calculation::parse(template).unwrap_or_else(|| panic!("{}", template));
+        assert_eq!(len, template.len());
+        let caps = regex.captures_from(input, 0).unwrap();
+        let mut s = String::new();
+        calc.write(&caps, &mut s).unwrap();
+        assert_eq!(s, expected, "{}", template);
+    }
+
+    let (calc, _) = Calculation::parse("%<count*2>").unwrap();
+    let caps = regex.captures_from("1 x1", 0).unwrap();
+    match calc.write(&caps, &mut String::new()) {
+        Err(CalcError::NotANumber(msg)) => assert_eq!(msg, "'%<count*2>': capture group 'count' is not a number: \"x1\""),
+        x => panic!("{:?}", x),
+    };
+    assert_eq!(calc.fallback(&caps), "x1");
+
+    assert!(Calculation::parse("%<1>").is_none());
+    assert!(Calculation::parse("%<1+>").is_none());
+    assert!(Calculation::parse("%<1+2").is_none());
+    assert!(Calculation::parse("%<1:q>").is_none());
+    assert!(Calculation::parse("%<1+abc>").is_none());
+
+    // nesting is limited rather than overflowing the stack
+    let nested = format!("%<1+{}2{}>", "(-".repeat(MAX_NESTING / 2), ")".repeat(MAX_NESTING / 2));
+    assert!(Calculation::parse(&nested).is_some());
+    let deep = format!("%<1+{}2{}>", "(".repeat(100_000), ")".repeat(100_000));
+    assert!(Calculation::parse(&deep).is_none());
+    assert!(Calculation::parse(&format!("%<1+{}2>", "-".repeat(100_000))).is_none());
+
+    let (calc, _) = Calculation::parse("%<1/0>").unwrap();
+    let caps = regex.captures_from("5 x", 0).unwrap();
+    assert_eq!(calc.write(&caps, &mut String::new()), Err(CalcError::Failed("'%<1/0>': division by zero".to_string())));
+
+    // `i128::MIN / -1` doesn't fit, nor does its remainder
+    let caps = regex.captures_from("-85070591730234615865843651857942052864 -1", 0).unwrap();
+    for template in &["%<1*2/%2>", "%<1*2%%2>"] {
+        let (calc, _) = Calculation::parse(template).unwrap();
+        assert_eq!(calc.write(&caps, &mut String::new()), Err(CalcError::Failed(format!("'{}': arithmetic overflow", template))));
+    }
+}
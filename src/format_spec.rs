@@ -0,0 +1,106 @@
+
+use regex::Regex;
+use lazy_static::lazy_static;
+
+lazy_static! {
+    static ref SPEC: Regex = Regex::new(r#"^((?P<fill>.)?(?P<align>[<>^]))?(?P<zero>0)?(?P<width>[0-9]+)?(\.(?P<max>[0-9]+))?$"#).unwrap();
+}
+
+#[derive(Clone,Copy,PartialEq,Eq,PartialOrd,Ord,Hash,Debug)]
+pub enum Align {
+    Left,
+    Right,
+    Center,
+}
+
+/// Padding and truncation of a capture, `[[fill]align][0][width][.max]`
+/// much like rust's `format!`. `>10` right aligns within 10 characters,
+/// `*^9` centers with `*` and `.5` keeps at most 5 characters. `08` pads
+/// with zeros after any sign, the same as a calculation's `08`.
+#[derive(Clone,Copy,PartialEq,Eq,PartialOrd,Ord,Hash,Debug)]
+pub struct FormatSpec {
+    pub fill: char,
+    pub align: Align,
+    pub zero: bool,
+    pub width: usize,
+    pub max: Option<usize>,
+}
+impl FormatSpec {
+
+    pub fn parse(arg: &str) -> Option<FormatSpec> {
+        let cap = SPEC.captures(arg)?;
+        let align = match cap.name("align").map(|m| m.as_str()) {
+            Option::Some(">") => Align::Right,
+            Option::Some("^") => Align::Center,
+            _ => Align::Left,
+        };
+        Some(FormatSpec {
+            fill: cap.name("fill").and_then(|m| m.as_str().chars().next()).unwrap_or(' '),
+            align,
+            zero: cap.name("zero").is_some(),
+            width: match cap.name("width") {
+                Option::None => 0,
+                Option::Some(m) => m.as_str().parse::<usize>().ok()?,
+            },
+            max: match cap.name("max") {
+                Option::None => None,
+                Option::Some(m) => Some(m.as_str().parse::<usize>().ok()?),
+            },
+        })
+    }
+
+    /// writes `arg` truncated to `max` and padded out to `width` characters
+    pub fn apply(&self, arg: &str, buffer: &mut String) {
+        let arg = match self.max {
+            Option::Some(max) => match arg.char_indices().nth(max) {
+                Option::Some((end, _)) => &arg[..end],
+                Option::None => arg,
+            },
+            Option::None => arg,
+        };
+        let padding = self.width.saturating_sub(arg.chars().count());
+        if self.zero {
+            let sign = if arg.starts_with(&['-', '+'][..]) { 1 } else { 0 };
+            buffer.push_str(&arg[..sign]);
+            buffer.extend(std::iter::repeat('0').take(padding));
+            buffer.push_str(&arg[sign..]);
+            return;
+        }
+        let (before, after) = match self.align {
+            Align::Left => (0, padding),
+            Align::Right => (padding, 0),
+            Align::Center => (padding / 2, padding - padding / 2),
+        };
+        buffer.extend(std::iter::repeat(self.fill).take(before));
+        buffer.push_str(arg);
+        buffer.extend(std::iter::repeat(self.fill).take(after));
+    }
+}
+
+#[test]
+fn test_format_spec() {
+    const DUT: &[(&str, &str, &str)] = &[
+        (">6", "abc", "   abc"),
+        ("<6", "abc", "abc   "),
+        ("6", "abc", "abc   "),
+        ("^7", "abc", "  abc  "),
+        ("*^6", "abc", "*abc**"),
+        ("0>5", "42", "00042"),
+        ("05", "42", "00042"),
+        ("05", "-42", "-0042"),
+        ("<08.3", "abcdef", "00000abc"),
+        ("0", "42", "42"),
+        (">>4", "a", ">>>a"),
+        (".2", "abcdef", "ab"),
+        ("<5.3", "abcdef", "abc  "),
+        (">2", "abcdef", "abcdef"),
+        ("-<4", "\u{2764}", "\u{2764}---"),
+    ];
+    for &(spec, input, expected) in DUT {
+        let mut s = String::new();
+        FormatSpec::parse(spec).unwrap().apply(input, &mut s);
+        assert_eq!(s, expected, "{:?} {:?}", spec, input);
+    }
+    assert!(FormatSpec::parse("abc").is_none());
+    assert!(FormatSpec::parse("<x").is_none());
+}
@@ -0,0 +1,504 @@
+
+use std::io;
+use std::borrow::Cow;
+use std::str::Chars;
+
+use regex::Regex;
+use lazy_static::lazy_static;
+
+lazy_static! {
+    static ref NUMBER: Regex = Regex::new(r#"^-?(0|[1-9][0-9]*)(\.[0-9]+)?([eE][+\-]?[0-9]+)?$"#).unwrap();
+}
+
+/// How deeply arrays and objects may nest, each level is walked by
+/// recursion so a deeper document is rejected instead of overflowing
+/// the stack.
+const MAX_DEPTH: usize = 512;
+
+/// A single step of a JSON path expression.
+#[derive(Clone,PartialEq,Eq,PartialOrd,Ord,Hash,Debug)]
+pub enum PathPart {
+    /// `.name` or `["name"]`
+    Key(String),
+    /// `[3]`
+    Index(usize),
+    /// `.*` or `[*]`, any member or element
+    Any,
+    /// `..`, zero or more levels
+    Descendant,
+}
+impl PathPart {
+    fn accepts(&self, seg: &Segment<'_>) -> bool {
+        match (self, seg) {
+            (&PathPart::Any, _) => true,
+            (PathPart::Key(k), Segment::Key(s)) => k == s,
+            (PathPart::Index(i), Segment::Index(j)) => i == j,
+            _ => false,
+        }
+    }
+}
+
+/// Selects values within a JSON document, a small subset of
+/// JSONPath: `$.servers[*].host`, `$..url`, `$["odd.key"][0]`.
+#[derive(Clone,PartialEq,Eq,PartialOrd,Ord,Hash,Debug)]
+pub struct JsonPath {
+    parts: Vec<PathPart>,
+}
+impl JsonPath {
+
+    pub fn parse(arg: &str) -> Result<JsonPath,String> {
+        let err = |pos: usize, msg: &str| format!("invalid json path '{}' at byte {}: {}", arg, pos, msg);
+        let b = arg.as_bytes();
+        let mut parts = Vec::new();
+        let mut pos = if b.first() == Some(&b'$') { 1 } else { 0 };
+        // a path may omit the leading `$.`
+        let mut need_sep = pos == 1;
+        while pos < b.len() {
+            match b[pos] {
+                b'.' => {
+                    pos += 1;
+                    if b.get(pos) == Some(&b'.') {
+                        parts.push(PathPart::Descendant);
+                        pos += 1;
+                    }
+                }
+                b'[' => {
+                    let end = match arg[pos..].find(']') {
+                        Option::None => return Err(err(pos, "unclosed `[`")),
+                        Option::Some(x) => pos + x,
+                    };
+                    let inner = arg[pos+1..end].trim();
+                    if inner == "*" {
+                        parts.push(PathPart::Any);
+                    } else if inner.len() >= 2 && (inner.starts_with('"') && inner.ends_with('"') || inner.starts_with('\'') && inner.ends_with('\'')) {
+                        parts.push(PathPart::Key(inner[1..inner.len()-1].to_string()));
+                    } else {
+                        match inner.parse::<usize>() {
+                            Ok(x) => parts.push(PathPart::Index(x)),
+                            Err(_) => return Err(err(pos, "expected an index, `*` or a quoted key")),
+                        };
+                    }
+                    pos = end + 1;
+                    need_sep = false;
+                    continue;
+                }
+                _ if need_sep => return Err(err(pos, "expected `.` or `[`")),
+                _ => { }
+            };
+            let start = pos;
+            while pos < b.len() && b[pos] != b'.' && b[pos] != b'[' {
+                pos += 1;
+            }
+            match &arg[start..pos] {
+                "" => {
+                    if parts.last() != Some(&PathPart::Descendant) || pos >= b.len() {
+                        return Err(err(start, "expected a key"));
+                    }
+                }
+                "*" => parts.push(PathPart::Any),
+                key => parts.push(PathPart::Key(key.to_string())),
+            };
+            need_sep = false;
+        }
+        if parts.last() == Some(&PathPart::Descendant) {
+            return Err(err(b.len(), "`..` must be followed by a key"));
+        }
+        Ok(JsonPath { parts })
+    }
+
+    fn is_match(&self, path: &[Segment<'_>]) -> bool {
+        fn inner(pattern: &[PathPart], path: &[Segment<'_>]) -> bool {
+            match pattern.split_first() {
+                Option::None => path.is_empty(),
+                Option::Some((&PathPart::Descendant, rest)) => {
+                    (0..=path.len()).any(|i| inner(rest, &path[i..]))
+                }
+                Option::Some((part, rest)) => {
+                    !path.is_empty() && part.accepts(&path[0]) && inner(rest, &path[1..])
+                }
+            }
+        }
+        inner(&self.parts, path)
+    }
+}
+
+#[derive(Clone,PartialEq,Eq,Debug)]
+enum Segment<'a> {
+    Key(Cow<'a,str>),
+    Index(usize),
+}
+
+/// Copies `doc` to `out` unchanged, except for the string values (and
+/// with `keys`, the member names) selected by `path`. Those are decoded
+/// and handed to `edit` along with where their contents start in `doc`,
+/// `edit` returns `None` to leave them as is.
+pub fn rewrite<F>(doc: &str, path: &JsonPath, keys: bool, out: &mut String, edit: F) -> io::Result<()>
+where
+    F: FnMut(&str, usize) -> Option<String>,
+{
+    let mut walker = Walker {
+        doc, path, keys, out, edit,
+        pos: 0,
+        stack: Vec::new(),
+    };
+    walker.skip_ws();
+    walker.value()?;
+    walker.skip_ws();
+    if walker.pos != doc.len() {
+        return Err(walker.error("trailing data after the document"));
+    }
+    Ok(())
+}
+
+struct Walker<'a,'o,F> {
+    doc: &'a str,
+    path: &'a JsonPath,
+    keys: bool,
+    out: &'o mut String,
+    edit: F,
+    pos: usize,
+    stack: Vec<Segment<'a>>,
+}
+impl<'a,'o,F: FnMut(&str, usize) -> Option<String>> Walker<'a,'o,F> {
+
+    fn error(&self, msg: &str) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, format!("invalid json at byte {}: {}", self.pos, msg))
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.doc.as_bytes().get(self.pos).copied()
+    }
+
+    /// copies everything from `start` up to the current position
+    fn copy_from(&mut self, start: usize) {
+        self.out.push_str(&self.doc[start..self.pos]);
+    }
+
+    fn skip_ws(&mut self) {
+        let start = self.pos;
+        while let Some(b' ') | Some(b'\t') | Some(b'\n') | Some(b'\r') = self.peek() {
+            self.pos += 1;
+        }
+        self.copy_from(start);
+    }
+
+    fn expect(&mut self, b: u8) -> io::Result<()> {
+        if self.peek() != Some(b) {
+            return Err(self.error(&format!("expected '{}'", b as char)));
+        }
+        self.pos += 1;
+        self.out.push(b as char);
+        Ok(())
+    }
+
+    fn value(&mut self) -> io::Result<()> {
+        match self.peek() {
+            Option::Some(b'{') |
+            Option::Some(b'[') if self.stack.len() >= MAX_DEPTH => {
+                Err(self.error(&format!("nested more than {} levels deep", MAX_DEPTH)))
+            }
+            Option::Some(b'{') => self.object(),
+            Option::Some(b'[') => self.array(),
+            Option::Some(b'"') => {
+                let selected = self.path.is_match(&self.stack);
+                self.string(selected)
+            }
+            Option::Some(_) => self.scalar(),
+            Option::None => Err(self.error("unexpected end of input")),
+        }
+    }
+
+    fn object(&mut self) -> io::Result<()> {
+        self.expect(b'{')?;
+        self.skip_ws();
+        if self.peek() == Some(b'}') {
+            return self.expect(b'}');
+        }
+        loop {
+            if self.peek() != Some(b'"') {
+                return Err(self.error("expected a member name"));
+            }
+            // the member name is selected when the member itself is
+            let key = self.string_key()?;
+            self.stack.push(Segment::Key(key));
+            self.skip_ws();
+            self.expect(b':')?;
+            self.skip_ws();
+            self.value()?;
+            self.stack.pop();
+            self.skip_ws();
+            match self.peek() {
+                Option::Some(b',') => {
+                    self.expect(b',')?;
+                    self.skip_ws();
+                }
+                Option::Some(b'}') => return self.expect(b'}'),
+                _ => return Err(self.error("expected ',' or '}'")),
+            };
+        }
+    }
+
+    fn string_key(&mut self) -> io::Result<Cow<'a,str>> {
+        let start = self.pos;
+        let raw = self.scan_string()?;
+        let key = decode(raw).ok_or_else(|| self.error("invalid escape in string"))?;
+        let selected = self.keys && {
+            self.stack.push(Segment::Key(key.clone()));
+            let m = self.path.is_match(&self.stack);
+            self.stack.pop();
+            m
+        };
+        self.emit_string(start, &key, selected);
+        Ok(key)
+    }
+
+    fn array(&mut self) -> io::Result<()> {
+        self.expect(b'[')?;
+        self.skip_ws();
+        if self.peek() == Some(b']') {
+            return self.expect(b']');
+        }
+        let mut index = 0usize;
+        loop {
+            self.stack.push(Segment::Index(index));
+            self.value()?;
+            self.stack.pop();
+            index += 1;
+            self.skip_ws();
+            match self.peek() {
+                Option::Some(b',') => {
+                    self.expect(b',')?;
+                    self.skip_ws();
+                }
+                Option::Some(b']') => return self.expect(b']'),
+                _ => return Err(self.error("expected ',' or ']'")),
+            };
+        }
+    }
+
+    fn scalar(&mut self) -> io::Result<()> {
+        let start = self.pos;
+        while let Some(b'a'..=b'z') | Some(b'0'..=b'9') | Some(b'-') | Some(b'+') | Some(b'.') | Some(b'E') = self.peek() {
+            self.pos += 1;
+        }
+        let token = &self.doc[start..self.pos];
+        if token == "true" || token == "false" || token == "null" || NUMBER.is_match(token) {
+            self.copy_from(start);
+            Ok(())
+        } else {
+            self.pos = start;
+            Err(self.error("expected a value"))
+        }
+    }
+
+    /// returns the raw contents of the string starting at the current
+    /// position (without quotes) and moves past it
+    fn scan_string(&mut self) -> io::Result<&'a str> {
+        let doc = self.doc;
+        let b = doc.as_bytes();
+        let start = self.pos + 1;
+        let mut pos = start;
+        loop {
+            match b.get(pos) {
+                Option::None => return Err(self.error("unterminated string")),
+                Option::Some(b'"') => break,
+                Option::Some(b'\\') => {
+                    match b.get(pos + 1) {
+                        Option::Some(b'"') | Option::Some(b'\\') | Option::Some(b'/') |
+                        Option::Some(b'b') | Option::Some(b'f') | Option::Some(b'n') |
+                        Option::Some(b'r') | Option::Some(b't') => pos += 2,
+                        Option::Some(b'u') if b.get(pos + 2..pos + 6).is_some_and(is_hex) => pos += 6,
+                        _ => {
+                            self.pos = pos;
+                            return Err(self.error("invalid escape in string"));
+                        }
+                    };
+                }
+                Option::Some(x) if *x < 0x20 => {
+                    self.pos = pos;
+                    return Err(self.error("control character in string"));
+                }
+                Option::Some(_) => pos += 1,
+            };
+        }
+        self.pos = pos + 1;
+        Ok(&doc[start..pos])
+    }
+
+    fn string(&mut self, selected: bool) -> io::Result<()> {
+        let start = self.pos;
+        let raw = self.scan_string()?;
+        if !selected {
+            self.copy_from(start);
+            return Ok(());
+        }
+        let decoded = decode(raw).ok_or_else(|| self.error("invalid escape in string"))?;
+        self.emit_string(start, &decoded, selected);
+        Ok(())
+    }
+
+    /// writes the string that was just scanned from `start`,
+    /// edited when it was selected
+    fn emit_string(&mut self, start: usize, decoded: &str, selected: bool) {
+        match selected.then(|| (self.edit)(decoded, start + 1)).flatten() {
+            Option::None => self.copy_from(start),
+            Option::Some(replacement) => encode(&replacement, self.out),
+        };
+    }
+}
+
+/// decodes the escapes within the contents of a JSON string
+fn decode(raw: &str) -> Option<Cow<'_,str>> {
+    if !raw.contains('\\') {
+        return Some(Cow::Borrowed(raw));
+    }
+    let mut s = String::with_capacity(raw.len());
+    let mut chars = raw.chars();
+    while let Some(c) = chars.next() {
+        s.push(decode_char(c, &mut chars)?);
+    }
+    Some(Cow::Owned(s))
+}
+
+/// Maps `offset`, a byte offset into the decoded contents of a JSON
+/// string, back to one into its `raw` contents.
+pub fn raw_offset(raw: &str, offset: usize) -> usize {
+    let mut chars = raw.chars();
+    let mut decoded = 0usize;
+    while decoded < offset {
+        match chars.next().and_then(|c| decode_char(c, &mut chars)) {
+            Option::None => break,
+            Option::Some(c) => decoded += c.len_utf8(),
+        };
+    }
+    raw.len() - chars.as_str().len()
+}
+
+/// decodes `c`, taking the rest of an escape it starts from `chars`
+fn decode_char(c: char, chars: &mut Chars<'_>) -> Option<char> {
+    if c != '\\' {
+        return Some(c);
+    }
+    match chars.next()? {
+        '"' => Some('"'),
+        '\\' => Some('\\'),
+        '/' => Some('/'),
+        'b' => Some('\u{08}'),
+        'f' => Some('\u{0C}'),
+        'n' => Some('\n'),
+        'r' => Some('\r'),
+        't' => Some('\t'),
+        'u' => {
+            let hi = take_hex(chars)?;
+            if (0xD800..0xDC00).contains(&hi) {
+                if chars.next() != Some('\\') || chars.next() != Some('u') {
+                    return None;
+                }
+                let lo = take_hex(chars)?;
+                if !(0xDC00..0xE000).contains(&lo) {
+                    return None;
+                }
+                char::from_u32(0x10000 + ((hi - 0xD800) << 10) + (lo - 0xDC00))
+            } else {
+                char::from_u32(hi)
+            }
+        }
+        _ => None,
+    }
+}
+
+fn take_hex(chars: &mut Chars<'_>) -> Option<u32> {
+    let hex = chars.as_str().get(..4).filter(|hex| is_hex(hex.as_bytes()))?;
+    let value = u32::from_str_radix(hex, 16).ok()?;
+    *chars = chars.as_str()[4..].chars();
+    Some(value)
+}
+
+/// whether `digits` are all hex, unlike `from_str_radix` a sign isn't taken
+fn is_hex(digits: &[u8]) -> bool {
+    digits.iter().all(u8::is_ascii_hexdigit)
+}
+
+/// writes `s` as a quoted JSON string
+fn encode(s: &str, out: &mut String) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            '\u{08}' => out.push_str("\\b"),
+            '\u{0C}' => out.push_str("\\f"),
+            x if (x as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", x as u32)),
+            x => out.push(x),
+        };
+    }
+    out.push('"');
+}
+
+#[test]
+fn test_json_path_parse() {
+    assert_eq!(JsonPath::parse("$.a.b").unwrap().parts, vec![PathPart::Key("a".to_string()), PathPart::Key("b".to_string())]);
+    assert_eq!(JsonPath::parse("a[*].b").unwrap().parts, vec![PathPart::Key("a".to_string()), PathPart::Any, PathPart::Key("b".to_string())]);
+    assert_eq!(JsonPath::parse("$..url").unwrap().parts, vec![PathPart::Descendant, PathPart::Key("url".to_string())]);
+    assert_eq!(JsonPath::parse("$[\"x.y\"][2]").unwrap().parts, vec![PathPart::Key("x.y".to_string()), PathPart::Index(2)]);
+    assert_eq!(JsonPath::parse("$").unwrap().parts, vec![]);
+    assert!(JsonPath::parse("$..").is_err());
+    assert!(JsonPath::parse("$[x]").is_err());
+    assert!(JsonPath::parse("$[1").is_err());
+    assert!(JsonPath::parse("$a").is_err());
+}
+
+#[test]
+fn test_json_rewrite() {
+    const DOC: &str = "{\n  \"name\": \"web\",\n  \"servers\" : [ {\"host\": \"a.example.com\", \"port\": 80},\n    {\"host\": \"b.\\u0065xample.com\", \"port\": 8080} ],\n  \"host\": \"example.com\"\n}\n";
+    let path = JsonPath::parse("$.servers[*].host").unwrap();
+    let mut out = String::new();
+    rewrite(DOC, &path, false, &mut out, |s, _| Some(s.replace("example", "test"))).unwrap();
+    assert_eq!(out, "{\n  \"name\": \"web\",\n  \"servers\" : [ {\"host\": \"a.test.com\", \"port\": 80},\n    {\"host\": \"b.test.com\", \"port\": 8080} ],\n  \"host\": \"example.com\"\n}\n");
+
+    // untouched values keep their original escapes
+    let mut out = String::new();
+    rewrite(DOC, &path, false, &mut out, |_, _| None).unwrap();
+    assert_eq!(out, DOC);
+
+    let path = JsonPath::parse("$..host").unwrap();
+    let mut out = String::new();
+    rewrite(DOC, &path, true, &mut out, |s, _| Some(format!("\"{}\"", s))).unwrap();
+    assert!(out.contains("{\"\\\"host\\\"\": \"\\\"a.example.com\\\"\""));
+    assert!(out.contains("\"\\\"host\\\"\": \"\\\"example.com\\\"\""));
+    assert!(out.contains("\"name\": \"web\""));
+
+    // where the contents of every edited string start
+    let mut starts = Vec::new();
+    rewrite(DOC, &path, false, &mut String::new(), |_, at| { starts.push(at); None }).unwrap();
+    assert_eq!(starts.iter().map(|&at| &DOC[at..at + 3]).collect::<Vec<_>>(), ["a.e", "b.\\", "exa"]);
+}
+
+#[test]
+fn test_json_raw_offset() {
+    let raw = r#"a\"b\u00e9\ud83d\ude00c"#;
+    let decoded = decode(raw).unwrap();
+    assert_eq!(decoded, "a\"b\u{e9}\u{1F600}c");
+    assert!(decode(r#"\u+041"#).is_none());
+    for &(offset, expected) in &[(0, 0), (1, 1), (2, 3), (3, 4), (5, 10), (9, 22), (10, 23)] {
+        assert_eq!(raw_offset(raw, offset), expected, "{}", offset);
+        assert_eq!(decode(&raw[..expected]).unwrap(), &decoded[..offset]);
+    }
+}
+
+#[test]
+fn test_json_rejects_invalid() {
+    let path = JsonPath::parse("$.a").unwrap();
+    for doc in &["{\"a\": }", "{\"a\": \"b\"", "[1, 2,]", "{\"a\": tru}", "\"x\" 1", "{\"a\": \"\\q\"}", "{\"b\": \"\\u+041\"}", "{\"a\": \"\\u12\"}"] {
+        let mut out = String::new();
+        assert!(rewrite(doc, &path, false, &mut out, |_, _| None).is_err(), "{}", doc);
+    }
+    let deep = "[".repeat(MAX_DEPTH + 1);
+    let err = rewrite(&deep, &path, false, &mut String::new(), |_, _| None).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    let nested = format!("{}{}", "[".repeat(MAX_DEPTH), "]".repeat(MAX_DEPTH));
+    assert!(rewrite(&nested, &path, false, &mut String::new(), |_, _| None).is_ok());
+}
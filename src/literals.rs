@@ -0,0 +1,142 @@
+
+use std::io;
+use std::collections::HashSet;
+
+use aho_corasick::{AhoCorasick,AhoCorasickBuilder,MatchKind};
+
+use crate::{
+    mapping::{Mapping,load_pairs},
+    matcher::{Matcher,Captures},
+};
+
+/// Matches many literal strings at once, every key of a two column
+/// file is searched for in a single pass and the leftmost, then longest,
+/// key found matches. `mapping` holds the value each key is replaced by.
+#[derive(Clone,Debug)]
+pub struct Literals {
+    searcher: AhoCorasick,
+    mapping: Mapping,
+    whole_words: bool,
+    longest_key: usize,
+}
+impl Literals {
+
+    /// Loads the pairs from `path` the same way as `Mapping::load`.
+    pub fn load(path: &str, case_insensitive: bool, whole_words: bool) -> io::Result<Literals> {
+        Literals::new(load_pairs(path)?, case_insensitive, whole_words)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("'{}' {}", path, e)))
+    }
+
+    /// `case_insensitive` only folds ASCII letters. A later pair
+    /// replaces an earlier one with the same key.
+    pub fn new(pairs: Vec<(String,String)>, case_insensitive: bool, whole_words: bool) -> Result<Literals,String> {
+        let mut seen = HashSet::with_capacity(pairs.len());
+        let mut keys = Vec::with_capacity(pairs.len());
+        for (key, _) in pairs.iter() {
+            if key.is_empty() {
+                return Err("has an empty key, every row needs text to search for".to_string());
+            }
+            if seen.insert(if case_insensitive { key.to_ascii_lowercase() } else { key.clone() }) {
+                keys.push(key.as_str());
+            }
+        }
+        if keys.is_empty() {
+            return Err("has no rows".to_string());
+        }
+        let longest_key = keys.iter().map(|key| key.len()).max().unwrap_or(0);
+        // overlapping matches are needed to fall back to a shorter
+        // key when the longest one isn't a whole word
+        let kind = if whole_words { MatchKind::Standard } else { MatchKind::LeftmostLongest };
+        let searcher = AhoCorasickBuilder::new()
+            .match_kind(kind)
+            .ascii_case_insensitive(case_insensitive)
+            .build(&keys);
+        let mapping = Mapping::new(pairs, case_insensitive);
+        Ok(Literals { searcher, mapping, whole_words, longest_key })
+    }
+
+    /// The value of every key, looked up by the text a match found.
+    pub fn mapping(&self) -> &Mapping {
+        &self.mapping
+    }
+
+    /// Finds the next key at or after `pos`, returning where it
+    /// starts and ends.
+    pub fn find_at(&self, hay: &str, pos: usize) -> Option<(usize,usize)> {
+        if !self.whole_words {
+            return self.searcher.find(&hay[pos..]).map(|m| (pos + m.start(), pos + m.end()));
+        }
+        // matches are reported by where they end, once one can't start
+        // before the best match so far there is no better match left
+        let mut best: Option<(usize,usize)> = None;
+        for m in self.searcher.find_overlapping_iter(&hay[pos..]) {
+            let (start, end) = (pos + m.start(), pos + m.end());
+            if let Some((s, _)) = best {
+                if end.saturating_sub(self.longest_key) > s {
+                    break;
+                }
+            }
+            if !is_whole_word(hay, start, end) {
+                continue;
+            }
+            best = match best {
+                Option::Some((s, e)) if s < start || (s == start && e >= end) => best,
+                _ => Some((start, end)),
+            };
+        }
+        best
+    }
+}
+impl Matcher for Literals {
+
+    fn group_names(&self) -> Vec<Option<&str>> {
+        vec![None]
+    }
+
+    fn find_from(&self, text: &str, start: usize) -> Option<(usize,usize)> {
+        self.find_at(text, start)
+    }
+
+    fn captures_from<'t>(&'t self, text: &'t str, start: usize) -> Option<Captures<'t>> {
+        let span = self.find_at(text, start)?;
+        Some(Captures::new(text, vec![Some(span)], self))
+    }
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// a match is a whole word when it isn't next to another word character
+fn is_whole_word(hay: &str, start: usize, end: usize) -> bool {
+    !hay[..start].chars().next_back().map(is_word_char).unwrap_or(false)
+        && !hay[end..].chars().next().map(is_word_char).unwrap_or(false)
+}
+
+#[test]
+fn test_literals_replace() {
+    use crate::{cap_groups::{CapGroup,Context},cap_iter::CapIter};
+
+    let pairs = |list: &[(&str, &str)]| list.iter().map(|&(k, v)| (k.to_string(), v.to_string())).collect::<Vec<_>>();
+    let caps = CapGroup::build_groups("%<0:map>");
+    let replace = |dut: &Literals, hay: &str, nice: bool| {
+        let ctx = Context { map: Some(dut.mapping()), ..Context::default() };
+        let mut out = String::new();
+        for item in CapIter::new(hay, dut, nice) {
+            item.output(&caps, &ctx, &mut out).unwrap();
+        }
+        out
+    };
+    let dut = Literals::new(pairs(&[("cat", "dog"), ("category", "kind"), ("a", "A"), ("cat", "mouse")]), false, false).unwrap();
+    assert_eq!(replace(&dut, "a category of cats", true), "A kind of mouses");
+    assert_eq!(replace(&dut, "a category of cats", false), "Akindmouse");
+    assert_eq!(replace(&dut, "nothing here", true), "nothing here");
+    assert!(!dut.is_match("nothing here"));
+
+    let dut = Literals::new(pairs(&[("cat", "dog"), ("cat food", "kibble")]), true, true).unwrap();
+    assert_eq!(replace(&dut, "Cat, CATS, cat foods, bobcat, Cat Food", true), "dog, CATS, dog foods, bobcat, kibble");
+    assert_eq!(dut.find_from("bobcat cat", 0), Some((7, 10)));
+
+    assert!(Literals::new(pairs(&[("", "x")]), false, false).is_err());
+    assert!(Literals::new(Vec::new(), false, false).is_err());
+}
@@ -1,15 +1,29 @@
 extern crate regex;
 
 pub mod cap_groups;
+pub mod case;
 mod cli;
 use cli::{WorkTodo,from_cli};
 pub mod work;
 pub mod buffered_reader;
+pub mod chunks;
 pub mod cap_iter;
+pub mod delimited;
+pub mod expr;
+pub mod format_spec;
+pub mod syntax;
+pub mod json;
+pub mod mapping;
+pub mod literals;
+pub mod matcher;
+pub mod pcre;
+pub mod pool;
+pub mod rules;
+pub mod source;
 
 //declare messages
-const VERS: &'static str = "1.0.0";
-const MSG: &'static str ="
+const VERS: &str = "1.0.0";
+const MSG: &str ="
 (not so) Simple Stream Editor
 
 sse is a stream editor that uses perl/python-esque regexes
@@ -75,6 +89,117 @@ CLI Options:
      f: file
      r: different file
 
+Long Options (between the flags and [REGEX], `--` ends them)
+
+     --csv[=FIELDS]
+        Write the selected capture groups as CSV rows
+        instead of using a [FORMAT STRING]. FIELDS is a
+        comma separated list of group numbers/names,
+        all groups are written when it is omitted.
+
+     --tsv[=FIELDS]
+        Same as `--csv` but tab separated.
+
+     --header
+        Write a header row of group names first.
+
+     --json=PATH
+        Input is a JSON document, only the string values
+        selected by PATH are edited, everything else is
+        copied as is. PATH is a JSONPath subset such as
+        `$.servers[*].host`, `$..url` or `$['a.b'][0]`.
+
+     --json-keys
+        With `--json`, member names selected by PATH are
+        edited as well.
+
+     --columns=N[,N...]
+        Field mode, every line is split into fields and
+        only the listed fields (starting at 1) are matched.
+        A matching field is replaced by [FORMAT STRING],
+        the rest of the line is kept as is.
+
+     --delimiter=C
+        Field separator for `--columns`, `,` by default.
+        `tab` or `\\t` for a tab. Fields may be quoted
+        with `\"` like CSV.
+
+     --pass-non-numeric
+        When arithmetic such as `%<1+1>` finds a group
+        which isn't a number, write the group unchanged
+        instead of stopping with an error.
+
+     --lenient
+        A format string referencing a group the regex
+        doesn't have is an error, this makes it a warning
+        and the reference is written as nothing.
+
+     --syntax=NAME
+        How [FORMAT STRING] refers to groups, one of
+        `native` (default, see below), `sed` (`\\1` `&`),
+        `perl` (`$1` `${name}` `$&`), `python` (`\\1`
+        `\\g<name>`) or `js` (`$1` `$<name>` `$&` `$$`).
+
+     --regex-file=PATH
+        Read [REGEX] from a file instead of an argument,
+        `-` reads it from stdin. One trailing line break
+        is removed.
+
+     --format-file=PATH
+        Read [FORMAT STRING] from a file, `-` for stdin.
+        It's used exactly as read, trailing line break
+        included, with `F` it's inserted byte for byte.
+
+     --map=FILE
+        Two column CSV (or `.tsv`) of old,new values used
+        by `%<N:map>`, loaded once before any input.
+
+     --map-strict
+        A capture missing from the `--map` file is an
+        error, by default it's written unchanged.
+
+     --literals=FILE
+        Replace every value in the first column of a two
+        column CSV (or `.tsv`) with the second column, all
+        at once. There is no [REGEX] or [FORMAT STRING],
+        files follow the options. Leftmost then longest
+        value wins, `S` ignores ASCII case.
+
+     --whole-words
+        With `--literals`, only replace values that
+        don't have a letter, digit or `_` on either side.
+
+     --rules=FILE
+        CSV (or `.tsv`) of [REGEX],[FORMAT STRING] rows,
+        taking the place of both arguments. Each line is
+        rewritten by the first row matching it, only
+        line-by-line. Lines are checked against every
+        regex at once before any of them runs.
+
+     --fancy
+        Compile [REGEX] with a backtracking engine which
+        supports lookaround `(?=..)` `(?<=..)` and
+        backreferences `\\1`. Slower, and only available
+        when sse is built with `--features fancy`.
+
+     --threads=N
+        With `f` every [FILE] given is processed, N at a
+        time, 0 (default) is one per core. Output is in
+        the order the files were given, a file which
+        fails is reported and the rest carry on.
+
+     --chunked[=SIZE]
+        Line-by-line only, the input is split into
+        chunks of about SIZE bytes (1M by default, or
+        such as `512K` `4M`) which are matched on
+        `--threads` threads. Output is the same as
+        without it, `%{count}` can't be used.
+
+     --no-mmap
+        Read input files instead of mapping them into
+        memory. Use it when another program may change
+        the file while sse is running.
+
 Example usage:
 
 $ sse -i [REGEX] [FORMAT STRING]
@@ -94,6 +219,11 @@ $ sse -inlwf [REGEX] [FORMAT STRING] [FILE]
     `lw`:      line-by-line (with Windows EOL)
     `f`:       write to a file as output
 
+$ sse -f --csv=date,2 --header [REGEX] [FILE]
+    `f`:       read from file
+    `--csv`:   write the `date` and `2` groups of each match as CSV
+    `--header`: first row is `date,2` (or the name of group 2)
+
 $ sse -fFncf [REGEX] [FORMAT] [FILE]
     `f`:       read from file
     `F`:       `[REGEX]` & `[FORMAT]` will be applied literally
@@ -103,11 +233,42 @@ $ sse -fFncf [REGEX] [FORMAT] [FILE]
 
 Regex Dialect:
 Internally sse uses Rust Regexes (Thanks to Burnt Sushi, Alex Crichton, and Huown).
+Perl's `\\Z` `\\h` `\\H` `\\Q..\\E` `(?<name>..)` and `(?#..)` are rewritten
+into the closest equivalent. Possessive `a++` and atomic `(?>..)` need `--fancy`.
 
 Format String Dialect:
 - Single Digit Capture Groups: `%0` -> `%9`
 - MultiDigit Capture Groups: `%<11>` -> `%<1009>`
 - Labelled Capture Groups: `%<mygroup>`
+  any name the regex accepts: `%<x>` `%<First_name>`
+- Entire match: `%&` (same as `%0`)
+- Text before/after the match: %` and %', within the line, or up
+  to the previous/next match in continuous mode
+- Fields of the current row (`--columns`): `%[1]` -> `%[N]`
+- Variables: `%{line}` `%{match}` (index on the line) `%{count}`
+  (index in the input) `%{offset}` (byte offset of the match)
+  `%{file}` `%{path}` (`-` for stdin) `%{env:NAME}`
+- Lookup in the `--map` file: `%<1:map>`, it may be followed
+  by a case conversion and padding `%<1:map:upper:>10>`
+- Case conversion of a group: `%<1:upper>` `%<name:lower>`
+  also `title`, `snake` and `camel`
+- `\\U` `\\L` upper/lower case everything up to `\\E`
+- `\\u` `\\l` upper/lower case the next character
+- Padding/truncation: `%<1:>10>` `%<name:*^8>` `%<2:<20.5>`
+  `[[fill]align][0][width][.max]`, align is `<` `>` or `^`,
+  `0` zero pads: `%<1:08>`
+- Default when a group is unset or empty: `%<1:-text>`
+- Conditional: `%<name?text if set:text if unset>`
+  the texts are templates, `\\:` `\\>` are literal `:` `>`
+- Arithmetic on numbers: `%<1+1>` `%<size*1000>` `%<1/(%2+1)>`
+  `+ - * / %` and `( )`, the first operand is a group, later
+  ones are numbers, `%N` or `%<name>`. Optionally formatted as
+  `%<1:#x>` `%<1*2:08>` `%<1/3:.2>`, `[#][0][width][.precision][d|x|X|o|b]`.
+  On a lone group the padding spec applies, `.2` keeps 2 chars.
+  A group which isn't a number is an error (see `--pass-non-numeric`).
+- Escapes: `\\t` `\\n` `\\r` `\\v` `\\f` `\\a` `\\e` `\\0` `\\\\`
+  octal `\\101`, hex `\\x41` (both up to 0x7F, there's no bytes mode),
+  `\\u{2014}` and by name `\\N{EM DASH}`
 - `%%` can be used to escape a capture group, solo `%` are not matched.
 
 Example usage:
@@ -0,0 +1,88 @@
+
+use std::collections::HashMap;
+use std::io;
+
+use crate::delimited::split_record;
+
+/// A lookup table loaded from a two column file, `%<N:map>` replaces
+/// a capture with the second column of the row whose first column is
+/// the capture's text.
+#[derive(Clone,Default,PartialEq,Eq,Debug)]
+pub struct Mapping {
+    table: HashMap<String,String>,
+    ascii_case_insensitive: bool,
+}
+impl Mapping {
+
+    /// With `ascii_case_insensitive` keys which only differ in ASCII
+    /// case are the same key, a later pair replaces an earlier one.
+    pub fn new(pairs: Vec<(String,String)>, ascii_case_insensitive: bool) -> Mapping {
+        let table = pairs.into_iter()
+            .map(|(key, value)| (if ascii_case_insensitive { key.to_ascii_lowercase() } else { key }, value))
+            .collect();
+        Mapping { table, ascii_case_insensitive }
+    }
+
+    /// Loads `path`, a `.tsv` file is tab separated anything else is
+    /// comma separated. Fields may be quoted like CSV, blank lines are
+    /// skipped and a later row replaces an earlier one with the same key.
+    pub fn load(path: &str) -> io::Result<Mapping> {
+        Ok(Mapping::new(load_pairs(path)?, false))
+    }
+
+    pub fn parse(text: &str, delimiter: char) -> Result<Mapping,String> {
+        Ok(Mapping::new(parse_pairs(text, delimiter)?, false))
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        let value = if self.ascii_case_insensitive {
+            self.table.get(&key.to_ascii_lowercase())
+        } else {
+            self.table.get(key)
+        };
+        value.map(|value| value.as_str())
+    }
+}
+
+/// Reads the rows of a two column file, see `Mapping::load`.
+pub fn load_pairs(path: &str) -> io::Result<Vec<(String,String)>> {
+    let text = std::fs::read_to_string(path)?;
+    let delimiter = if path.ends_with(".tsv") { '\t' } else { ',' };
+    parse_pairs(&text, delimiter)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("'{}' {}", path, e)))
+}
+
+pub fn parse_pairs(text: &str, delimiter: char) -> Result<Vec<(String,String)>,String> {
+    let mut pairs = Vec::new();
+    for (pos, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut fields = split_record(line, delimiter).into_iter().map(|(_, value)| value);
+        match (fields.next(), fields.next(), fields.next()) {
+            (Option::Some(key), Option::Some(value), Option::None) => {
+                pairs.push((key.into_owned(), value.into_owned()));
+            }
+            _ => return Err(format!("line {} should have 2 fields separated by {:?}", pos + 1, delimiter)),
+        };
+    }
+    Ok(pairs)
+}
+
+#[test]
+fn test_mapping_parse() {
+    let map = Mapping::parse("old,new\n\n\"a,b\",c\nold,newer\n", ',').unwrap();
+    assert_eq!(map.get("old"), Some("newer"));
+    assert_eq!(map.get("a,b"), Some("c"));
+    assert_eq!(map.get("missing"), None);
+
+    let map = Mapping::parse("x\ty\r\n", '\t').unwrap();
+    assert_eq!(map.get("x"), Some("y"));
+
+    assert_eq!(Mapping::parse("a,b\nc\n", ','), Err("line 2 should have 2 fields separated by ','".to_string()));
+    assert!(Mapping::parse("a,b,c\n", ',').is_err());
+
+    let map = Mapping::new(vec![("Old".to_string(), "a".to_string()), ("OLD".to_string(), "b".to_string())], true);
+    assert_eq!(map.get("old"), Some("b"));
+    assert_eq!(Mapping::new(vec![("Old".to_string(), "a".to_string())], false).get("old"), None);
+}
@@ -0,0 +1,288 @@
+
+#[cfg(feature = "fancy")]
+use std::{collections::HashMap,sync::Mutex,thread::{self,ThreadId}};
+
+use regex::Regex;
+
+/// A regex engine, or anything else which finds matches with capture
+/// groups. Output only reads matches through this, so an engine can
+/// be swapped without touching the format string logic. It's shared by
+/// every thread working on the input.
+pub trait Matcher: Sync {
+
+    /// The name of every group in order, group 0 is the entire
+    /// match and is never named.
+    fn group_names(&self) -> Vec<Option<&str>>;
+
+    /// The index of the group called `name`.
+    fn group_index(&self, name: &str) -> Option<usize> {
+        self.group_names().iter().position(|n| *n == Some(name))
+    }
+
+    /// Where the first match starting at or after `start` begins and ends.
+    /// Like `^` or `\b` the text before `start` is still looked at.
+    fn find_from(&self, text: &str, start: usize) -> Option<(usize,usize)>;
+
+    /// The groups of the first match starting at or after `start`.
+    fn captures_from<'t>(&'t self, text: &'t str, start: usize) -> Option<Captures<'t>>;
+
+    fn captures_len(&self) -> usize {
+        self.group_names().len()
+    }
+
+    fn is_match(&self, text: &str) -> bool {
+        self.find_from(text, 0).is_some()
+    }
+
+    /// An engine which can fail part way through a search, such as a
+    /// backtracking one giving up, reports no match and keeps why here
+    /// for the thread which searched.
+    fn take_error(&self) -> Option<String> {
+        None
+    }
+}
+
+impl Matcher for Regex {
+
+    fn group_names(&self) -> Vec<Option<&str>> {
+        self.capture_names().collect()
+    }
+
+    fn group_index(&self, name: &str) -> Option<usize> {
+        self.capture_names().position(|n| n == Some(name))
+    }
+
+    fn find_from(&self, text: &str, start: usize) -> Option<(usize,usize)> {
+        self.find_at(text, start).map(|m| (m.start(), m.end()))
+    }
+
+    fn captures_from<'t>(&'t self, text: &'t str, start: usize) -> Option<Captures<'t>> {
+        let mut locs = self.capture_locations();
+        self.captures_read_at(&mut locs, text, start)?;
+        let spans = (0..locs.len()).map(|i| locs.get(i)).collect();
+        Some(Captures { text, spans, matcher: self })
+    }
+
+    fn captures_len(&self) -> usize {
+        Regex::captures_len(self)
+    }
+}
+
+/// A backtracking engine, slower than `Regex` but with lookaround
+/// and backreferences.
+#[cfg(feature = "fancy")]
+#[derive(Debug)]
+pub struct Fancy {
+    regex: fancy_regex::Regex,
+    errors: Mutex<HashMap<ThreadId,String>>,
+}
+#[cfg(feature = "fancy")]
+impl Fancy {
+    pub fn new(regex: fancy_regex::Regex) -> Fancy {
+        Fancy { regex, errors: Mutex::new(HashMap::new()) }
+    }
+
+    fn failed<T>(&self, e: fancy_regex::Error) -> Option<T> {
+        let mut errors = self.errors.lock().unwrap_or_else(|e| e.into_inner());
+        errors.entry(thread::current().id()).or_insert_with(|| e.to_string());
+        None
+    }
+}
+#[cfg(feature = "fancy")]
+impl Matcher for Fancy {
+
+    fn group_names(&self) -> Vec<Option<&str>> {
+        self.regex.capture_names().collect()
+    }
+
+    fn find_from(&self, text: &str, start: usize) -> Option<(usize,usize)> {
+        match self.regex.find_from_pos(text, start) {
+            Ok(m) => m.map(|m| (m.start(), m.end())),
+            Err(e) => self.failed(e),
+        }
+    }
+
+    fn captures_from<'t>(&'t self, text: &'t str, start: usize) -> Option<Captures<'t>> {
+        let caps = match self.regex.captures_from_pos(text, start) {
+            Ok(caps) => caps?,
+            Err(e) => return self.failed(e),
+        };
+        let spans = (0..caps.len()).map(|i| caps.get(i).map(|m| (m.start(), m.end()))).collect();
+        Some(Captures { text, spans, matcher: self })
+    }
+
+    fn captures_len(&self) -> usize {
+        self.regex.captures_len()
+    }
+
+    fn take_error(&self) -> Option<String> {
+        self.errors.lock().unwrap_or_else(|e| e.into_inner()).remove(&thread::current().id())
+    }
+}
+
+/// Matches a string exactly, used by `F`.
+#[derive(Clone,PartialEq,Eq,Debug)]
+pub struct Literal {
+    needle: String,
+}
+impl Literal {
+    pub fn new(needle: &str) -> Literal {
+        Literal { needle: needle.to_string() }
+    }
+}
+impl Matcher for Literal {
+
+    fn group_names(&self) -> Vec<Option<&str>> {
+        vec![None]
+    }
+
+    fn find_from(&self, text: &str, start: usize) -> Option<(usize,usize)> {
+        text[start..].find(self.needle.as_str())
+            .map(|pos| (start + pos, start + pos + self.needle.len()))
+    }
+
+    fn captures_from<'t>(&'t self, text: &'t str, start: usize) -> Option<Captures<'t>> {
+        let span = self.find_from(text, start)?;
+        Some(Captures { text, spans: vec![Some(span)], matcher: self })
+    }
+}
+
+/// The groups of one match, whichever `Matcher` found it.
+#[derive(Clone)]
+pub struct Captures<'t> {
+    text: &'t str,
+    spans: Vec<Option<(usize,usize)>>,
+    matcher: &'t dyn Matcher,
+}
+impl<'t> Captures<'t> {
+
+    /// Built by a `Matcher`, `spans[0]` is the entire match and
+    /// a group which didn't participate is `None`.
+    pub fn new(text: &'t str, spans: Vec<Option<(usize,usize)>>, matcher: &'t dyn Matcher) -> Self {
+        Captures { text, spans, matcher }
+    }
+
+    pub fn get(&self, i: usize) -> Option<Match<'t>> {
+        self.spans.get(i)
+            .and_then(|span| *span)
+            .map(|(start, end)| Match { text: self.text, start, end })
+    }
+
+    pub fn name(&self, name: &str) -> Option<Match<'t>> {
+        self.matcher.group_index(name).and_then(|i| self.get(i))
+    }
+
+    pub fn len(&self) -> usize {
+        self.spans.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.spans.is_empty()
+    }
+}
+
+/// Where a single group matched.
+#[derive(Clone,Copy,PartialEq,Eq,Debug)]
+pub struct Match<'t> {
+    text: &'t str,
+    start: usize,
+    end: usize,
+}
+impl<'t> Match<'t> {
+
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    pub fn end(&self) -> usize {
+        self.end
+    }
+
+    pub fn as_str(&self) -> &'t str {
+        &self.text[self.start..self.end]
+    }
+}
+
+/// Every match in a text, an empty match directly after
+/// the previous match is skipped the same way `regex` does.
+pub struct CaptureMatches<'t> {
+    matcher: &'t dyn Matcher,
+    text: &'t str,
+    last_end: usize,
+    last_match: Option<usize>,
+}
+impl<'t> CaptureMatches<'t> {
+    pub fn new(matcher: &'t dyn Matcher, text: &'t str) -> Self {
+        CaptureMatches { matcher, text, last_end: 0, last_match: None }
+    }
+}
+impl<'t> Iterator for CaptureMatches<'t> {
+    type Item = Captures<'t>;
+    fn next(&mut self) -> Option<Captures<'t>> {
+        loop {
+            if self.last_end > self.text.len() {
+                return None;
+            }
+            let caps = self.matcher.captures_from(self.text, self.last_end)?;
+            let (start, end) = match caps.get(0) {
+                Option::None => return None,
+                Option::Some(m) => (m.start(), m.end()),
+            };
+            if start == end {
+                // step over the next character so the search moves on
+                self.last_end = end + self.text[end..].chars().next().map(|c| c.len_utf8()).unwrap_or(1);
+                if Some(end) == self.last_match {
+                    continue;
+                }
+            } else {
+                self.last_end = end;
+            }
+            self.last_match = Some(end);
+            return Some(caps);
+        }
+    }
+}
+
+#[test]
+fn test_capture_matches() {
+    let regex = Regex::new(r#"(?P<word>\w+)|(\d)?-"#).unwrap();
+    let found = CaptureMatches::new(&regex, "ab -x")
+        .map(|caps| (caps.get(0).unwrap().as_str(), caps.name("word").map(|m| m.as_str()), caps.get(2).is_some()))
+        .collect::<Vec<_>>();
+    assert_eq!(found, vec![("ab", Some("ab"), false), ("-", None, false), ("x", Some("x"), false)]);
+
+    // the same empty matches as the `regex` crate
+    let regex = Regex::new(r#"a*"#).unwrap();
+    for text in &["", "baaab", "aé", "éa"] {
+        let expected = regex.find_iter(text).map(|m| (m.start(), m.end())).collect::<Vec<_>>();
+        let found = CaptureMatches::new(&regex, text)
+            .map(|caps| caps.get(0).map(|m| (m.start(), m.end())).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(found, expected, "{:?}", text);
+    }
+
+    let literal = Literal::new("a.");
+    let found = CaptureMatches::new(&literal, "a.ba.a")
+        .map(|caps| caps.get(0).unwrap().start())
+        .collect::<Vec<_>>();
+    assert_eq!(found, vec![0, 3]);
+    assert_eq!(literal.captures_len(), 1);
+}
+
+#[cfg(feature = "fancy")]
+#[test]
+fn test_fancy() {
+    let fancy = Fancy::new(fancy_regex::Regex::new(r#"(?P<word>\w+) \k<word>(?= )"#).unwrap());
+    let found = CaptureMatches::new(&fancy, "the the cat cat. no no ")
+        .map(|caps| (caps.get(0).unwrap().as_str(), caps.name("word").unwrap().as_str()))
+        .collect::<Vec<_>>();
+    assert_eq!(found, vec![("the the", "the"), ("no no", "no")]);
+    assert_eq!(fancy.group_names(), vec![None, Some("word")]);
+    assert_eq!(fancy.take_error(), None);
+
+    // gives up backtracking, which is kept as an error
+    let fancy = Fancy::new(fancy_regex::RegexBuilder::new(r#"(a|aa)*\1b"#).backtrack_limit(100).build().unwrap());
+    assert_eq!(fancy.find_from(&"a".repeat(30), 0), None);
+    assert!(fancy.take_error().is_some());
+    assert_eq!(fancy.take_error(), None);
+}
@@ -0,0 +1,378 @@
+
+use std::fmt;
+
+/// What the translated pattern is compiled with, some idioms
+/// only have an equivalent in one engine or one matching mode.
+#[derive(Clone,Copy,PartialEq,Eq,Default,Debug)]
+pub struct Target {
+    /// `--fancy`, which backtracks and has lookaround
+    pub backtracking: bool,
+    /// line-by-line, a line never holds a line break
+    pub lines: bool,
+    /// `\p{..}` classes are available
+    pub unicode: bool,
+    /// the `x` flag is set for the whole pattern
+    pub extended: bool,
+}
+
+/// Perl syntax which has no equivalent, `start..end` is
+/// the offending part of the pattern.
+#[derive(Clone,PartialEq,Eq,Debug)]
+pub struct PcreError {
+    pattern: String,
+    start: usize,
+    end: usize,
+    message: String,
+}
+impl fmt::Display for PcreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // only the line holding the span is shown
+        let line_start = self.pattern[..self.start].rfind('\n').map(|x| x + 1).unwrap_or(0);
+        let line_end = self.pattern[self.start..].find('\n').map(|x| self.start + x).unwrap_or(self.pattern.len());
+        let indent = self.pattern[line_start..self.start].chars().count();
+        let width = self.pattern[self.start..self.end.min(line_end)].chars().count().max(1);
+        writeln!(f, "perl regex syntax error:")?;
+        writeln!(f, "    {}", &self.pattern[line_start..line_end])?;
+        writeln!(f, "    {}{}", " ".repeat(indent), "^".repeat(width))?;
+        write!(f, "error: {}", self.message)
+    }
+}
+
+/// Rewrites the Perl idioms the engines don't understand into their
+/// equivalents, everything else is left for the engine to parse.
+///
+/// * `\Z` is `\z` line-by-line, `(?=\n?\z)` with `--fancy`
+/// * `\h` `\H` horizontal whitespace
+/// * `\Q..\E` quoted text
+/// * `(?<name>..)` named groups
+/// * `(?#..)` comments, `#` comments with `x` are left as is
+///
+/// Possessive `*+` `++` `?+` `{n,m}+` and atomic `(?>..)` only
+/// mean something to a backtracking engine, without `--fancy`
+/// they're an error.
+pub fn translate(pattern: &str, target: Target) -> Result<String,PcreError> {
+    let mut t = Translator {
+        pattern, target,
+        out: String::with_capacity(pattern.len() + 8),
+        pos: 0,
+        class_depth: 0,
+        extended: vec![target.extended],
+        quantified: false,
+    };
+    t.run()?;
+    Ok(t.out)
+}
+
+struct Translator<'a> {
+    pattern: &'a str,
+    target: Target,
+    out: String,
+    pos: usize,
+    /// nesting of `[..]`, `regex` allows classes within classes
+    class_depth: usize,
+    /// whether `x` is set, one entry per open group
+    extended: Vec<bool>,
+    /// the last thing written was a quantifier, so `+` makes it possessive
+    quantified: bool,
+}
+impl<'a> Translator<'a> {
+
+    fn error<T>(&self, start: usize, end: usize, message: String) -> Result<T,PcreError> {
+        Err(PcreError { pattern: self.pattern.to_string(), start, end, message })
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.pattern[self.pos..]
+    }
+
+    /// copies `len` bytes of the pattern unchanged
+    fn copy(&mut self, len: usize) {
+        self.out.push_str(&self.pattern[self.pos..self.pos + len]);
+        self.pos += len;
+    }
+
+    fn run(&mut self) -> Result<(),PcreError> {
+        while let Some(c) = self.rest().chars().next() {
+            let quantified = std::mem::replace(&mut self.quantified, false);
+            if self.class_depth > 0 {
+                self.class(c)?;
+                continue;
+            }
+            match c {
+                '\\' => self.escape()?,
+                '[' => {
+                    self.class_depth = 1;
+                    self.copy(1);
+                    self.class_start();
+                }
+                '(' => self.group()?,
+                ')' => {
+                    if self.extended.len() > 1 {
+                        self.extended.pop();
+                    }
+                    self.copy(1);
+                }
+                '+' if quantified => {
+                    // possessive
+                    if !self.target.backtracking {
+                        return self.error(self.pos, self.pos + 1, "a possessive quantifier never gives back what it matched, which needs '--fancy'".to_string());
+                    }
+                    self.copy(1);
+                }
+                '*' | '+' | '?' => {
+                    self.copy(1);
+                    // `??` `*?` are lazy, not a new quantifier
+                    if !quantified {
+                        self.quantified = true;
+                    }
+                }
+                '{' => {
+                    let len = repetition_len(self.rest());
+                    if len == 0 {
+                        self.copy(1);
+                    } else {
+                        self.copy(len);
+                        self.quantified = true;
+                    }
+                }
+                '#' if self.is_extended() => {
+                    // the engine skips the comment, so it's copied without a look
+                    let len = self.rest().find('\n').unwrap_or(self.rest().len());
+                    self.copy(len);
+                }
+                _ => self.copy(c.len_utf8()),
+            };
+        }
+        Ok(())
+    }
+
+    fn is_extended(&self) -> bool {
+        self.extended.last().copied().unwrap_or(false)
+    }
+
+    /// a `]` or `^]` straight after `[` is part of the class
+    fn class_start(&mut self) {
+        if self.rest().starts_with('^') {
+            self.copy(1);
+        }
+        if self.rest().starts_with(']') {
+            self.copy(1);
+        }
+    }
+
+    fn class(&mut self, c: char) -> Result<(),PcreError> {
+        match c {
+            '\\' => self.escape()?,
+            '[' if self.rest().starts_with("[:") => {
+                // `[:alpha:]`
+                match self.rest()[2..].find(":]") {
+                    Option::Some(x) => self.copy(x + 4),
+                    Option::None => self.copy(1),
+                };
+            }
+            '[' => {
+                self.class_depth += 1;
+                self.copy(1);
+                self.class_start();
+            }
+            ']' => {
+                self.class_depth -= 1;
+                self.copy(1);
+            }
+            _ => self.copy(c.len_utf8()),
+        };
+        Ok(())
+    }
+
+    fn escape(&mut self) -> Result<(),PcreError> {
+        let start = self.pos;
+        let c = match self.pattern[start + 1..].chars().next() {
+            Option::None => {
+                // a trailing `\` is the engine's error to report
+                self.copy(1);
+                return Ok(());
+            }
+            Option::Some(c) => c,
+        };
+        let in_class = self.class_depth > 0;
+        match c {
+            'Z' if !in_class => {
+                if self.target.lines {
+                    self.out.push_str(r"\z");
+                } else if self.target.backtracking {
+                    self.out.push_str(r"(?=\n?\z)");
+                } else {
+                    return self.error(start, start + 2, r"`\Z` also matches before a final line break, which needs lookahead. Use `\z` for the very end, `$` for the end of any line, or '--fancy'".to_string());
+                }
+                self.pos += 2;
+            }
+            'h' | 'H' => {
+                let blank = if self.target.unicode { r"\t\p{Zs}" } else { r"\t " };
+                match (c, in_class) {
+                    ('h', true) => self.out.push_str(blank),
+                    ('h', false) => {
+                        self.out.push('[');
+                        self.out.push_str(blank);
+                        self.out.push(']');
+                    }
+                    _ => {
+                        self.out.push_str("[^");
+                        self.out.push_str(blank);
+                        self.out.push(']');
+                    }
+                };
+                self.pos += 2;
+            }
+            'Q' => {
+                let quoted = &self.pattern[start + 2..];
+                let len = quoted.find(r"\E").unwrap_or(quoted.len());
+                self.out.push_str(&regex::escape(&quoted[..len]));
+                self.pos = start + 2 + (len + 2).min(quoted.len());
+            }
+            'E' => {
+                // a `\E` without `\Q` does nothing in perl
+                self.pos += 2;
+            }
+            'K' | 'G' if !self.target.backtracking => {
+                let what = if c == 'K' { "keeping text out of the match" } else { "anchoring at the end of the previous match" };
+                return self.error(start, start + 2, format!("`\\{}` ({}) needs '--fancy'", c, what));
+            }
+            _ => self.copy(1 + c.len_utf8()),
+        };
+        Ok(())
+    }
+
+    fn group(&mut self) -> Result<(),PcreError> {
+        let start = self.pos;
+        let rest = self.rest();
+        let extended = self.is_extended();
+        if rest.starts_with("(?#") {
+            // perl ends the comment at the first `)`
+            match rest.find(')') {
+                Option::Some(x) => self.pos += x + 1,
+                Option::None => return self.error(start, self.pattern.len(), "the comment is never closed by `)`".to_string()),
+            };
+            return Ok(());
+        }
+        if rest.starts_with("(?<") && !rest.starts_with("(?<=") && !rest.starts_with("(?<!") {
+            self.out.push_str("(?P<");
+            self.pos += 3;
+            self.extended.push(extended);
+            return Ok(());
+        }
+        if rest.starts_with("(?>") {
+            if !self.target.backtracking {
+                return self.error(start, start + 3, "an atomic group never gives back what it matched, which needs '--fancy'".to_string());
+            }
+            self.copy(3);
+            self.extended.push(extended);
+            return Ok(());
+        }
+        let unsupported = if rest.starts_with("(*") {
+            Some((2, "backtracking control verbs such as `(*SKIP)` aren't supported"))
+        } else if rest.starts_with("(?|") {
+            Some((3, "branch reset groups aren't supported"))
+        } else if rest.starts_with("(?(") {
+            Some((3, "conditional groups aren't supported"))
+        } else if rest.starts_with("(?R") || rest.starts_with("(?&") || rest.starts_with("(?P>")
+            || rest.strip_prefix("(?").map(|r| r.trim_start_matches(&['+', '-'][..]).starts_with(|c: char| c.is_ascii_digit())).unwrap_or(false)
+        {
+            Some((3, "recursion and subroutine calls aren't supported"))
+        } else {
+            None
+        };
+        if let Some((len, message)) = unsupported {
+            return self.error(start, start + len, message.to_string());
+        }
+        // flags, `(?x)` applies to the rest of the enclosing group
+        // and `(?x:..)` to just the new one
+        if let Some(flags) = rest.strip_prefix("(?") {
+            let len = flags.find(|c: char| !(c.is_ascii_alphabetic() || c == '-')).unwrap_or(flags.len());
+            let mut value = extended;
+            let mut on = true;
+            for c in flags[..len].chars() {
+                match c {
+                    '-' => on = false,
+                    'x' => value = on,
+                    _ => { }
+                };
+            }
+            match flags[len..].chars().next() {
+                Option::Some(')') => {
+                    if let Some(last) = self.extended.last_mut() {
+                        *last = value;
+                    }
+                    self.copy(len + 3);
+                    return Ok(());
+                }
+                Option::Some(':') => {
+                    self.extended.push(value);
+                    self.copy(len + 3);
+                    return Ok(());
+                }
+                _ => { }
+            };
+        }
+        self.extended.push(extended);
+        self.copy(1);
+        Ok(())
+    }
+}
+
+/// the length of a `{n}` `{n,}` `{n,m}` counted repetition
+/// at the start of `arg`, 0 when it isn't one
+fn repetition_len(arg: &str) -> usize {
+    let end = match arg.find('}') {
+        Option::None => return 0,
+        Option::Some(end) => end,
+    };
+    let inner = &arg[1..end];
+    let mut parts = inner.splitn(2, ',');
+    let first = parts.next().unwrap_or("");
+    let valid = !first.is_empty() && first.bytes().all(|b| b.is_ascii_digit())
+        && parts.next().map(|second| second.bytes().all(|b| b.is_ascii_digit())).unwrap_or(true);
+    if valid { end + 1 } else { 0 }
+}
+
+#[test]
+fn test_translate() {
+    let regex = Target { unicode: true, ..Target::default() };
+    let fancy = Target { backtracking: true, ..regex };
+    const DUT: &[(&str, &str, &str)] = &[
+        // pattern, regex, fancy
+        (r"(?<year>\d{4})-(?<!x)", r"(?P<year>\d{4})-(?<!x)", r"(?P<year>\d{4})-(?<!x)"),
+        (r"\h+\H[\h\d]", r"[\t\p{Zs}]+[^\t\p{Zs}][\t\p{Zs}\d]", r"[\t\p{Zs}]+[^\t\p{Zs}][\t\p{Zs}\d]"),
+        (r"a++b*+c?+d{2,3}+e+?", r"", r"a++b*+c?+d{2,3}+e+?"),
+        (r"a+?b{2}\{1}+", r"a+?b{2}\{1}+", r"a+?b{2}\{1}+"),
+        (r"(?>a|ab)c", r"", r"(?>a|ab)c"),
+        (r"\Q1+1=[2]\E?\Q.", r"1\+1=\[2\]?\.", r"1\+1=\[2\]?\."),
+        (r"a(?# a comment)b", r"ab", r"ab"),
+        (r"[]+[:alpha:]\Z]+\Z", r"", r"[]+[:alpha:]\Z]+(?=\n?\z)"),
+        ("(?x) a+ # \\Z (?<x>\n b", "(?x) a+ # \\Z (?<x>\n b", "(?x) a+ # \\Z (?<x>\n b"),
+        ("(?x: a # \\Z\n) # \\h", "(?x: a # \\Z\n) # [\\t\\p{Zs}]", "(?x: a # \\Z\n) # [\\t\\p{Zs}]"),
+    ];
+    for &(pattern, expected_regex, expected_fancy) in DUT {
+        match translate(pattern, regex) {
+            Ok(x) => assert_eq!(x, expected_regex, "{}", pattern),
+            Err(e) => assert_eq!("", expected_regex, "{}: {}", pattern, e),
+        };
+        match translate(pattern, fancy) {
+            Ok(x) => assert_eq!(x, expected_fancy, "{}", pattern),
+            Err(e) => assert_eq!("", expected_fancy, "{}: {}", pattern, e),
+        };
+    }
+    let lines = Target { lines: true, ..Target::default() };
+    assert_eq!(translate(r"x\Z\h", lines).unwrap(), r"x\z[\t ]");
+
+    let e = translate("ab\\Kc", regex).unwrap_err();
+    assert_eq!((e.start, e.end), (2, 4));
+    assert_eq!(e.to_string(), "perl regex syntax error:\n    ab\\Kc\n      ^^\nerror: `\\K` (keeping text out of the match) needs '--fancy'");
+    let e = translate("ab{2}+", regex).unwrap_err();
+    assert_eq!((e.start, e.end), (5, 6));
+    let e = translate("a(?>b)", regex).unwrap_err();
+    assert_eq!((e.start, e.end), (1, 4));
+    assert!(e.to_string().ends_with("error: an atomic group never gives back what it matched, which needs '--fancy'"));
+    let e = translate("(a)(?1)", fancy).unwrap_err();
+    assert_eq!((e.start, e.end), (3, 6));
+    assert!(translate("(?-i:a)(?i)b(?P<x>c)", regex).is_ok());
+}
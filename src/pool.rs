@@ -0,0 +1,197 @@
+
+use std::io::{self,Write};
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::panic::{self,AssertUnwindSafe};
+use std::slice;
+use std::sync::{mpsc,Mutex,MutexGuard};
+use std::thread;
+
+use crate::cli::{InitialFlagOptions,Output};
+
+/// The number of threads for `--threads`, `0` is one per core.
+pub fn threads(requested: usize) -> usize {
+    match requested {
+        0 => thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+        n => n,
+    }
+}
+
+/// Every file is rendered into a buffer by `render` on one of the
+/// `--threads` workers, which all share the compiled regex and format
+/// string. Written back files are written by the worker, otherwise the
+/// buffers are written to the output in the order the files were given.
+/// A file which can't be processed is reported with its path, in the
+/// same order, and the rest carry on.
+pub fn for_each_file<F>(opts: &InitialFlagOptions, files: &[String], render: F) -> io::Result<()>
+where
+    F: Fn(usize, &[String], &mut dyn Write) -> io::Result<()> + Sync,
+{
+    let mut output = opts.output.open_for_stream(false, files)?;
+    let mut failed = 0usize;
+    let threads = threads(opts.threads);
+    let work = |index: usize, file: &String| render_file(opts, index, slice::from_ref(file), &render);
+    ordered(files.iter(), threads, threads * 4, work, |index, result| {
+        match (result, output.as_mut()) {
+            (Ok(buffer), Option::Some(o)) => o.write_all(&buffer),
+            (Ok(_), Option::None) => Ok(()),
+            (Err(e), o) => {
+                // anything before it is written first
+                if let Some(o) = o {
+                    o.flush()?;
+                }
+                failed += 1;
+                eprintln!("{}: {}", files[index], e);
+                Ok(())
+            }
+        }
+    })?;
+    if let Some(mut o) = output {
+        o.flush()?;
+    }
+    match failed {
+        0 => Ok(()),
+        n => Err(io::Error::other(format!("{} of {} files couldn't be processed", n, files.len()))),
+    }
+}
+
+fn render_file<F>(opts: &InitialFlagOptions, index: usize, file: &[String], render: &F) -> io::Result<Vec<u8>>
+where
+    F: Fn(usize, &[String], &mut dyn Write) -> io::Result<()>,
+{
+    let mut buffer = Vec::with_capacity(4096);
+    render(index, file, &mut buffer)?;
+    if opts.output == Output::SameFile {
+        let mut output = opts.output.open_output(false, file)?;
+        output.write_all(&buffer)?;
+        output.flush()?;
+        buffer.clear();
+    }
+    Ok(buffer)
+}
+
+/// Runs `work` on every item of `jobs` with `threads` threads, each
+/// result is handed to `done` in the order of `jobs`. The jobs are taken
+/// and the results handed over on the calling thread, at most `window`
+/// jobs are taken before the oldest of them is done, however uneven
+/// they are. The first error from `done`, or a job which panicked,
+/// stops any further jobs and is returned.
+pub fn ordered<I,T,F,G>(jobs: I, threads: usize, window: usize, work: F, mut done: G) -> io::Result<()>
+where
+    I: Iterator,
+    I::Item: Send,
+    T: Send,
+    F: Fn(usize, I::Item) -> T + Sync,
+    G: FnMut(usize, T) -> io::Result<()>,
+{
+    let window = window.max(1);
+    let (send_job, recv_job) = mpsc::channel::<(usize, I::Item)>();
+    let recv_job = Mutex::new(recv_job);
+    thread::scope(|scope| {
+        // dropped when this returns, which is what stops the workers
+        let send_job = send_job;
+        let (send_result, recv_result) = mpsc::channel();
+        for _ in 0..threads.max(1) {
+            let send_result = send_result.clone();
+            let (recv_job, work) = (&recv_job, &work);
+            scope.spawn(move || loop {
+                let (index, item) = match lock(recv_job).recv() {
+                    Ok(job) => job,
+                    Err(_) => break,
+                };
+                // a panic is handed over like a result, so the
+                // calling thread doesn't wait for it forever
+                let value = panic::catch_unwind(AssertUnwindSafe(|| work(index, item)));
+                if send_result.send((index, value)).is_err() {
+                    break;
+                }
+            });
+        }
+        std::mem::drop(send_result);
+
+        let mut jobs = jobs.enumerate().fuse();
+        let mut pending = BTreeMap::new();
+        let (mut taken, mut next) = (0usize, 0usize);
+        loop {
+            while taken < next + window {
+                match jobs.next() {
+                    Option::None => break,
+                    Option::Some(job) => {
+                        if send_job.send(job).is_err() {
+                            break;
+                        }
+                        taken += 1;
+                    }
+                };
+            }
+            if next == taken {
+                return Ok(());
+            }
+            let (index, value) = match recv_result.recv() {
+                Ok(result) => result,
+                Err(_) => return Err(io::Error::other("the worker threads stopped")),
+            };
+            pending.insert(index, value);
+            while let Some(value) = pending.remove(&next) {
+                match value {
+                    Ok(value) => done(next, value)?,
+                    Err(e) => return Err(io::Error::other(format!("a worker thread panicked: {}", panic_message(&*e)))),
+                };
+                next += 1;
+            }
+        }
+    })
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    match payload.downcast_ref::<&str>() {
+        Option::Some(s) => s,
+        Option::None => payload.downcast_ref::<String>().map(|s| s.as_str()).unwrap_or("unknown cause"),
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_,T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[test]
+fn test_ordered() {
+    // later jobs finish first, but are still handed over in order
+    let mut found = Vec::new();
+    ordered(0..40usize, 4, 6, |index, job| {
+        thread::sleep(std::time::Duration::from_micros(((40 - job) * 50) as u64));
+        (index, job * 2)
+    }, |index, value| {
+        found.push((index, value));
+        Ok(())
+    }).unwrap();
+    assert_eq!(found, (0..40).map(|i| (i, (i, i * 2))).collect::<Vec<_>>());
+
+    // an error stops the rest
+    let mut count = 0;
+    let res = ordered(0..1000usize, 3, 2, |_, job| job, |_, job| {
+        count += 1;
+        if job == 5 { Err(io::Error::other("stop")) } else { Ok(()) }
+    });
+    assert_eq!(res.unwrap_err().to_string(), "stop");
+    assert_eq!(count, 6);
+
+    // failed jobs finish in any order, the first one is returned
+    for _ in 0..20 {
+        let res = ordered(0..50usize, 8, 16, |_, job| {
+            thread::sleep(std::time::Duration::from_micros(((50 - job) * 20) as u64));
+            if job % 10 == 3 { Err(io::Error::other(format!("job {}", job))) } else { Ok(job) }
+        }, |_, result| result.map(|_| ()));
+        assert_eq!(res.unwrap_err().to_string(), "job 3");
+    }
+
+    // a job which panics stops the rest, rather than leaving
+    // the calling thread waiting for its result
+    let mut count = 0;
+    let res = ordered(0..100usize, 3, 4, |_, job| if job == 7 { panic!("job {}", job) } else { job }, |_, _| {
+        count += 1;
+        Ok(())
+    });
+    assert_eq!(res.unwrap_err().to_string(), "a worker thread panicked: job 7");
+    assert_eq!(count, 7);
+}
@@ -0,0 +1,51 @@
+
+use regex::{Regex,RegexSet};
+
+/// The cache for the set's lazy DFA. Unicode classes such as `\w` in
+/// a few dozen patterns overflow the default 2 MiB, the DFA then gives
+/// up and the set ends up many times slower than running each regex.
+pub const SET_DFA_SIZE_LIMIT: usize = 16 << 20;
+
+/// Many regexes, each with its own format string, run over the same
+/// input. A line is handled by the first rule which matches it, the
+/// `RegexSet` of every pattern finds that rule in a single pass so
+/// lines matching nothing never run the individual regexes.
+#[derive(Clone,Debug)]
+pub struct Rules {
+    set: RegexSet,
+    regexes: Vec<Regex>,
+}
+impl Rules {
+
+    /// `set` must hold the same patterns, built with the same flags, as `regexes`.
+    pub fn new(set: RegexSet, regexes: Vec<Regex>) -> Rules {
+        assert_eq!(set.len(), regexes.len());
+        Rules { set, regexes }
+    }
+
+    /// The index of the first rule matching `text`.
+    pub fn first_match(&self, text: &str) -> Option<usize> {
+        if !self.set.is_match(text) {
+            return None;
+        }
+        self.set.matches(text).into_iter().next()
+    }
+
+    pub fn regex(&self, rule: usize) -> &Regex {
+        &self.regexes[rule]
+    }
+}
+
+#[test]
+fn test_rules_first_match() {
+    let patterns = &[r#"^ERROR (\d+)"#, r#"(\d+)$"#, r#"^WARN"#];
+    let rules = Rules::new(
+        RegexSet::new(patterns).unwrap(),
+        patterns.iter().map(|p| Regex::new(p).unwrap()).collect(),
+    );
+    assert_eq!(rules.first_match("ERROR 5 at 10"), Some(0));
+    assert_eq!(rules.first_match("WARN at 10"), Some(1));
+    assert_eq!(rules.first_match("WARN"), Some(2));
+    assert_eq!(rules.first_match("INFO"), None);
+    assert_eq!(rules.regex(1).as_str(), r#"(\d+)$"#);
+}
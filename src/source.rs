@@ -0,0 +1,90 @@
+
+use std::fs::File;
+use std::io::{self,Read,BufReader};
+
+use memmap2::Mmap;
+
+use crate::cli::Input;
+
+/// An opened input. A regular file is mapped into memory where it can
+/// be, so continuous mode matches it in place and copies nothing but
+/// the output, anything else is read.
+pub enum Source {
+    Mapped(Mmap),
+    Reader(BufReader<Box<dyn Read>>),
+}
+impl Source {
+
+    /// Opens the input, stdin or the file `stack[0]`, which is
+    /// mapped when `map` is set and it can be.
+    pub fn open(input: &Input, stack: &[String], map: bool) -> io::Result<Source> {
+        if *input == Input::File && map {
+            let file = File::open(&stack[0])?;
+            return Ok(match map_file(&file) {
+                Option::Some(map) => Source::Mapped(map),
+                Option::None => Source::Reader(BufReader::with_capacity(32 * 1024, Box::new(file))),
+            });
+        }
+        input.open_input(stack).map(Source::Reader)
+    }
+
+    /// For reading line by line.
+    pub fn into_reader(self) -> BufReader<Box<dyn Read>> {
+        match self {
+            Source::Mapped(map) => BufReader::with_capacity(32 * 1024, Box::new(io::Cursor::new(map))),
+            Source::Reader(reader) => reader,
+        }
+    }
+
+    /// All of the input, borrowed from the mapping or read into `buffer`.
+    pub fn read_all<'s>(&'s mut self, buffer: &'s mut String) -> io::Result<&'s str> {
+        match self {
+            Source::Mapped(map) => std::str::from_utf8(map)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "stream did not contain valid UTF-8")),
+            Source::Reader(reader) => {
+                reader.read_to_string(buffer)?;
+                Ok(buffer.as_str())
+            }
+        }
+    }
+}
+
+/// Pipes, devices and empty files can't be mapped, nor can
+/// some file systems, all of them are read instead.
+fn map_file(file: &File) -> Option<Mmap> {
+    let metadata = file.metadata().ok()?;
+    if !metadata.is_file() || metadata.len() == 0 {
+        return None;
+    }
+    // Safety: the file must not be truncated while it's mapped, sse
+    // only writes back to it once the mapping is gone. Another program
+    // changing it at the same time can't be guarded against, which
+    // `--no-mmap` avoids.
+    unsafe { Mmap::map(file) }.ok()
+}
+
+#[test]
+fn test_source_read_all() {
+    let path = std::env::temp_dir().join(format!("sse-source-{}.txt", std::process::id()));
+    std::fs::write(&path, "mapped\ntext").unwrap();
+    let stack = vec![path.to_string_lossy().into_owned()];
+
+    let mut source = Source::open(&Input::File, &stack, true).unwrap();
+    assert!(matches!(source, Source::Mapped(_)));
+    let mut buffer = String::new();
+    assert_eq!(source.read_all(&mut buffer).unwrap(), "mapped\ntext");
+    assert!(buffer.is_empty());
+
+    let mut source = Source::open(&Input::File, &stack, false).unwrap();
+    assert!(matches!(source, Source::Reader(_)));
+    assert_eq!(source.read_all(&mut buffer).unwrap(), "mapped\ntext");
+
+    std::fs::write(&path, [b'a', 0xff]).unwrap();
+    let mut source = Source::open(&Input::File, &stack, true).unwrap();
+    assert_eq!(source.read_all(&mut String::new()).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+    // nothing to map
+    std::fs::write(&path, "").unwrap();
+    assert!(matches!(Source::open(&Input::File, &stack, true).unwrap(), Source::Reader(_)));
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,176 @@
+
+use regex::{Regex,Captures};
+use lazy_static::lazy_static;
+
+use crate::cap_groups::CapGroup;
+
+lazy_static! {
+    static ref SED: Regex = Regex::new(r#"^((\x5C(?P<digit>[0-9]))|(?P<entire>&)|(\x5C(?P<literal>&)))"#).unwrap();
+    static ref PERL: Regex = Regex::new(r#"^((\$(?P<digit>[0-9]+))|(\$\{(?P<braceddigit>[0-9]+)\})|(\$\{(?P<label>[_A-Za-z][_A-Za-z0-9.\[\]]*)\})|(\$(?P<entire>&))|(\$(?P<before>`))|(\$(?P<after>'))|(\x5C(?P<literal>[$@])))"#).unwrap();
+    static ref PYTHON: Regex = Regex::new(r#"^((\x5Cg<(?P<braceddigit>[0-9]+)>)|(\x5Cg<(?P<label>[_A-Za-z][_A-Za-z0-9.\[\]]*)>)|(\x5C(?P<digit>[1-9][0-9]?)))"#).unwrap();
+    static ref JS: Regex = Regex::new(r#"^((\$(?P<digit>[0-9]{1,2}))|(\$<(?P<label>[_A-Za-z][_A-Za-z0-9.\[\]]*)>)|(\$(?P<entire>&))|(\$(?P<before>`))|(\$(?P<after>'))|(\$(?P<literal>\$)))"#).unwrap();
+}
+
+/// How a format string refers to capture groups. Every syntax builds
+/// the same groups, so output doesn't depend on which one is used.
+#[derive(Clone,Copy,PartialEq,Eq,PartialOrd,Ord,Hash,Debug)]
+pub enum Syntax {
+    /// `%1` `%<name>` and everything else in the format string dialect
+    Native,
+    /// `\1` `&`, `\&` is a literal `&`
+    Sed,
+    /// `$1` `${1}` `${name}` `$&` `` $` `` `$'`, `\$` is a literal `$`
+    Perl,
+    /// `\1` `\g<1>` `\g<name>`
+    Python,
+    /// `$1` `$<name>` `$&` `` $` `` `$'` `$$`, backslashes are not escapes
+    Js,
+}
+impl Syntax {
+
+    pub fn from_name(name: &str) -> Option<Syntax> {
+        match name {
+            "native" | "sse" => Some(Syntax::Native),
+            "sed" => Some(Syntax::Sed),
+            "perl" => Some(Syntax::Perl),
+            "python" => Some(Syntax::Python),
+            "js" | "javascript" => Some(Syntax::Js),
+            _ => None,
+        }
+    }
+
+    pub fn build_groups<'a>(&self, arg: &'a str) -> Vec<CapGroup<'a>> {
+        let reference: &Regex = match *self {
+            Syntax::Native => return CapGroup::build_groups(arg),
+            Syntax::Sed => &SED,
+            Syntax::Perl => &PERL,
+            Syntax::Python => &PYTHON,
+            Syntax::Js => &JS,
+        };
+        let mut todo_list = Vec::with_capacity(1);
+        let mut literal = 0usize;
+        let mut pos = 0usize;
+        while let Some(c) = arg[pos..].chars().next() {
+            if let Some(cap) = reference.captures(&arg[pos..]) {
+                self.literal(&arg[literal..pos], &mut todo_list);
+                let end = match cap.get(0) {
+                    Option::None => unsafe { std::hint::unreachable_unchecked() },
+                    Option::Some(m) => m.end(),
+                };
+                todo_list.push(Self::build(cap));
+                pos += end;
+                literal = pos;
+                continue;
+            }
+            match (c, arg[pos+1..].chars().next()) {
+                // the escape is left for `SPECIAL`,
+                // skipping it keeps `\\1` from being a group
+                ('\\', Option::Some(next)) if *self != Syntax::Js => pos += 1 + next.len_utf8(),
+                _ => pos += c.len_utf8(),
+            };
+        }
+        self.literal(&arg[literal..], &mut todo_list);
+        todo_list
+    }
+
+    fn literal<'a>(&self, arg: &'a str, todo_list: &mut Vec<CapGroup<'a>>) {
+        if arg.is_empty() {
+            return;
+        }
+        match self {
+            &Syntax::Js => todo_list.push(CapGroup::CopyFromInput(arg)),
+            _ => CapGroup::build_spec_groups(arg, todo_list),
+        };
+    }
+
+    fn build<'a>(cap: Captures<'a>) -> CapGroup<'a> {
+        if let Some(m) = cap.name("literal") {
+            CapGroup::Escape(m.as_str())
+        } else if cap.name("entire").is_some() {
+            CapGroup::SingleDigit(0)
+        } else if cap.name("before").is_some() {
+            CapGroup::Before
+        } else if cap.name("after").is_some() {
+            CapGroup::After
+        } else if let Some(m) = cap.name("label") {
+            CapGroup::Labelled(m.as_str())
+        } else {
+            let m = match cap.name("digit").or_else(|| cap.name("braceddigit")) {
+                Option::None => unsafe { std::hint::unreachable_unchecked() },
+                Option::Some(m) => m,
+            };
+            match m.as_str().parse::<usize>() {
+                Ok(x) if m.as_str().len() == 1 => CapGroup::SingleDigit(x),
+                Ok(x) => CapGroup::MultiDigit(x),
+                // too many digits to be a group
+                Err(_) => CapGroup::CopyFromInput(cap.get(0).map(|m| m.as_str()).unwrap_or("")),
+            }
+        }
+    }
+}
+
+#[test]
+fn test_syntaxes_build_the_same_groups() {
+    let expected = vec![
+        CapGroup::SingleDigit(2),
+        CapGroup::CopyFromInput("-"),
+        CapGroup::SingleDigit(1),
+        CapGroup::SingleChar('\t'),
+        CapGroup::SingleDigit(0),
+    ];
+    const DUT: &[(Syntax, &str)] = &[
+        (Syntax::Native, r#"%2-%1\t%0"#),
+        (Syntax::Sed, r#"\2-\1\t&"#),
+        (Syntax::Perl, r#"$2-${1}\t$&"#),
+        (Syntax::Python, r#"\g<2>-\1\t\g<0>"#),
+    ];
+    for &(syntax, template) in DUT {
+        assert_eq!(syntax.build_groups(template), expected, "{:?}", syntax);
+    }
+    // js has no escapes, the tab is written as is
+    assert_eq!(Syntax::Js.build_groups("$2-$1\t$&"), vec![
+        CapGroup::SingleDigit(2),
+        CapGroup::CopyFromInput("-"),
+        CapGroup::SingleDigit(1),
+        CapGroup::CopyFromInput("\t"),
+        CapGroup::SingleDigit(0),
+    ]);
+}
+
+#[test]
+fn test_syntax_references() {
+    assert_eq!(Syntax::Sed.build_groups(r#"\U\1\E \& \\1 %1"#), vec![
+        CapGroup::CaseSpan(crate::case::Case::Upper),
+        CapGroup::SingleDigit(1),
+        CapGroup::CaseEnd,
+        CapGroup::CopyFromInput(" "),
+        CapGroup::Escape("&"),
+        CapGroup::CopyFromInput(" "),
+        CapGroup::SingleChar('\\'),
+        CapGroup::CopyFromInput("1 %1"),
+    ]);
+    assert_eq!(Syntax::Perl.build_groups(r#"${name}$12$`$'\$1"#), vec![
+        CapGroup::Labelled("name"),
+        CapGroup::MultiDigit(12),
+        CapGroup::Before,
+        CapGroup::After,
+        CapGroup::Escape("$"),
+        CapGroup::CopyFromInput("1"),
+    ]);
+    assert_eq!(Syntax::Python.build_groups(r#"\g<first_name> \12\n"#), vec![
+        CapGroup::Labelled("first_name"),
+        CapGroup::CopyFromInput(" "),
+        CapGroup::MultiDigit(12),
+        CapGroup::SingleChar('\n'),
+    ]);
+    assert_eq!(Syntax::Js.build_groups(r#"$<name> $$1 $123 \n"#), vec![
+        CapGroup::Labelled("name"),
+        CapGroup::CopyFromInput(" "),
+        CapGroup::Escape("$"),
+        CapGroup::CopyFromInput("1 "),
+        CapGroup::MultiDigit(12),
+        CapGroup::CopyFromInput("3 \\n"),
+    ]);
+    assert_eq!(Syntax::from_name("javascript"), Some(Syntax::Js));
+    assert_eq!(Syntax::from_name("awk"), None);
+}
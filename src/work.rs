@@ -1,69 +1,245 @@
 #[allow(unused_imports)]
-use std::io::{self,Seek,Write,Read,BufWriter};
-
-use regex::Regex;
+use std::io::{self,Seek,Write,Read,BufReader,BufWriter};
 
 use crate::{
-    cli::{InitialFlagOptions},
+    cli::{InitialFlagOptions,Output},
     buffered_reader::{BufferedReader},
-    cap_groups::CapGroup,
-    cap_iter::CapIter,
+    chunks::{Chunk,Chunks},
+    cap_groups::{CapGroup,Context,Around,Position},
+    cap_iter::{CapIter,CapOut},
+    delimited::{self,split_record,quote_field},
+    json::{self,JsonPath},
+    mapping::Mapping,
+    literals::Literals,
+    matcher::Matcher,
+    pool,
+    rules::Rules,
+    source::Source,
 };
 
 pub fn do_work(
     opts: &InitialFlagOptions,
-    regex: &Regex,
+    regex: &dyn Matcher,
     caps: &[CapGroup<'_>],
+    header: Option<&str>,
     stack: &[String]
 ) -> io::Result<()> {
+    let map = load_map(opts)?;
+    run(opts, stack, |stack, input, first, writer| {
+        let ctx = context(opts, map.as_ref(), stack);
+        match opts.json {
+            Option::Some(ref path) => render_json(opts, path, input, regex, caps, &ctx, writer)?,
+            Option::None => render_work(opts, input, regex, caps, if first { header } else { None }, &ctx, writer)?,
+        };
+        search_failed(regex)
+    })
+}
+
+/// Line-by-line or continuous matching of one input.
+fn render_work(
+    opts: &InitialFlagOptions,
+    mut input: Source,
+    regex: &dyn Matcher,
+    caps: &[CapGroup<'_>],
+    header: Option<&str>,
+    ctx: &Context<'_>,
+    writer: &mut dyn Write,
+) -> io::Result<()> {
+    match opts.matching.term() {
+        Option::Some(term) => {
+            write_header(header, term, writer)?;
+            render_lines(opts, input, term, ctx, writer, |reader, writer, ctx| {
+                do_lines(opts, reader, writer, term, regex, caps, ctx)?;
+                // only the thread which searched sees it fail,
+                // the error goes back with the chunk's output
+                search_failed(regex)
+            })
+        }
+        Option::None => {
+            let mut s = String::new();
+            let text = input.read_all(&mut s)?;
+            write_header(header, b"\n", writer)?;
+            for item in CapIter::new(text, regex, opts.nice) {
+                item.stream_output(caps, ctx, writer)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Hands the lines of `input` to `render`. With `--chunked` they're
+/// split into chunks which are rendered on many threads, `ctx.position`
+/// is then where the chunk starts and each chunk's output is written
+/// in order. An error rendering a chunk, such as a `--fancy` search
+/// giving up, is that chunk's result so only the first one in order
+/// is returned, from the calling thread.
+fn render_lines<F>(
+    opts: &InitialFlagOptions,
+    input: Source,
+    term: &'static [u8],
+    ctx: &Context<'_>,
+    writer: &mut dyn Write,
+    render: F,
+) -> io::Result<()>
+where
+    F: Fn(BufferedReader<Box<dyn Read + '_>>, &mut dyn Write, &Context<'_>) -> io::Result<()> + Sync,
+{
+    let size = match opts.chunk_size {
+        Option::None => return render(BufferedReader::new(input.into_reader(), term), writer, ctx),
+        Option::Some(size) => size,
+    };
+    let threads = pool::threads(opts.threads);
+    let work = |_, chunk: io::Result<Chunk>| -> io::Result<Vec<u8>> {
+        let chunk = chunk?;
+        let mut out = Vec::with_capacity(chunk.text.len());
+        let text: Box<dyn Read + '_> = Box::new(chunk.text.as_slice());
+        let ctx = Context { position: chunk.start, ..*ctx };
+        render(BufferedReader::new(BufReader::new(text), term), &mut out, &ctx)?;
+        Ok(out)
+    };
+    pool::ordered(Chunks::new(input.into_reader(), term, size), threads, threads * 2, work, |_, out| {
+        writer.write_all(&out?)
+    })
+}
+
+/// Runs `render` over every input. A single input streams straight to
+/// the output, many are handed to the worker pool. `first` is if the
+/// output starts with what `render` writes, only then is there a header.
+fn run<F>(opts: &InitialFlagOptions, stack: &[String], render: F) -> io::Result<()>
+where
+    F: Fn(&[String], Source, bool, &mut dyn Write) -> io::Result<()> + Sync,
+{
+    if opts.output != Output::DifferentFile && stack.len() > 1 {
+        // written back every file is an output of its own
+        let own_output = opts.output == Output::SameFile;
+        return pool::for_each_file(opts, stack, |index, file, writer| {
+            render(file, open_source(opts, file)?, own_output || index == 0, writer)
+        });
+    }
     let input_is_stdin = opts.input.is_stdin();
-    let input = opts.input.open_input(stack)?;
-    let input_streamable = opts.matching.build_input_stream(input);
-    let output_streamable = opts.output.open_for_stream(input_is_stdin,stack)?;
-    match (input_streamable,output_streamable) {
-        (Ok((i,term)),Some(mut o)) => {
-            do_streamable(i,&mut o, term, opts.nice, regex, caps)?;
-            o.flush()?;
+    let input = open_source(opts, stack)?;
+    match opts.output.open_for_stream(input_is_stdin, stack)? {
+        Option::Some(mut o) => {
+            render(stack, input, true, &mut o)?;
+            o.flush()
         }
-        (Ok((i,term)),None) => {
-            let mut cursor = std::io::Cursor::new(Vec::with_capacity(4096)); 
-            do_streamable(i, &mut cursor, term, opts.nice, regex, caps)?;
+        Option::None => {
+            // writing back, nothing is opened until all the input is read
+            let mut buffer = Vec::with_capacity(4096);
+            render(stack, input, true, &mut buffer)?;
             let mut output = opts.output.open_output(input_is_stdin, stack)?;
-            let v = cursor.into_inner();
-            output.write_all(v.as_slice())?;
-            output.flush()?;
-        },
-        (Err(mut i),Some(mut o)) => {
-            let mut s = String::with_capacity(4096);
-            i.read_to_string(&mut s)?;
-            let buffer = s.as_str();
-            let cap_iter = regex.captures_iter(buffer);
-            for item in CapIter::new(buffer, cap_iter,opts.nice) {
-                item.stream_output(caps, &mut o)?;
-            }
-            o.flush()?;
+            output.write_all(&buffer)?;
+            output.flush()
         }
-        (Err(mut i),None) => {
-            let mut out_str = String::with_capacity(4096);
+    }
+}
 
-            let mut s = String::with_capacity(4096);
-            {
-                i.read_to_string(&mut s)?;
-                std::mem::drop(i);
-            }
+/// A redirected output may be the input, truncated while it's
+/// mapped reading the input would fault.
+fn open_source(opts: &InitialFlagOptions, stack: &[String]) -> io::Result<Source> {
+    Source::open(&opts.input, stack, opts.mmap && opts.output != Output::DifferentFile)
+}
 
-            let buffer = s.as_str();
-            let cap_iter = regex.captures_iter(buffer);
-            for item in CapIter::new(buffer, cap_iter,opts.nice) {
-                item.output(caps, &mut out_str);
-            }
+fn context<'c>(opts: &InitialFlagOptions, map: Option<&'c Mapping>, stack: &'c [String]) -> Context<'c> {
+    Context {
+        pass_non_numeric: opts.pass_non_numeric,
+        path: opts.input.path(stack),
+        map,
+        map_strict: opts.map_strict,
+        ..Context::default()
+    }
+}
 
-            let mut output = opts.output.open_output(input_is_stdin, stack)?;
-            output.write_all(out_str.as_bytes())?;
-            output.flush()?;
+/// loaded once, every match looks up the same table
+fn load_map(opts: &InitialFlagOptions) -> io::Result<Option<Mapping>> {
+    match opts.map {
+        Option::None => Ok(None),
+        Option::Some(ref path) => Mapping::load(path).map(Some),
+    }
+}
+
+/// `--rules`, each line is rewritten by the first rule which matches it.
+pub fn do_rules(
+    opts: &InitialFlagOptions,
+    rules: &Rules,
+    caps: &[Vec<CapGroup<'_>>],
+    stack: &[String]
+) -> io::Result<()> {
+    let map = load_map(opts)?;
+    let select = |line: &str| rules.first_match(line).map(|rule| (rules.regex(rule) as &dyn Matcher, caps[rule].as_slice()));
+    run(opts, stack, |stack, input, _, writer| {
+        let ctx = context(opts, map.as_ref(), stack);
+        match opts.matching.term() {
+            Option::Some(term) => render_lines(opts, input, term, &ctx, writer, |reader, writer, ctx| {
+                do_streamable(reader, writer, term, opts.nice, false, select, ctx)
+            }),
+            Option::None => Err(io::Error::other("'--rules' works line-by-line")),
+        }
+    })
+}
+
+/// Edits only the string values of a JSON document selected by `path`,
+/// the rest of the document is copied as is.
+fn render_json(
+    opts: &InitialFlagOptions,
+    path: &JsonPath,
+    mut input: Source,
+    regex: &dyn Matcher,
+    caps: &[CapGroup<'_>],
+    ctx: &Context<'_>,
+    writer: &mut dyn Write,
+) -> io::Result<()> {
+    let mut s = String::new();
+    let text = input.read_all(&mut s)?;
+
+    let mut out_str = String::with_capacity(text.len());
+    // the first error stops any further edits, and is returned afterwards
+    let mut error: Option<io::Error> = None;
+    // matches are placed within the whole document, a string
+    // is always on a single line of it
+    let mut position = Position { line: 1, ..Position::default() };
+    let mut lines_counted = 0usize;
+    json::rewrite(text, path, opts.json_keys, &mut out_str, |value, at| {
+        if error.is_some() || !regex.is_match(value) {
+            return None;
+        }
+        let lines = text.as_bytes()[lines_counted..at].iter().filter(|&&b| b == b'\n').count();
+        if lines != 0 {
+            position.line += lines;
+            position.match_index = 0;
         }
+        lines_counted = at;
+        let mut edited = String::with_capacity(value.len());
+        for item in CapIter::new(value, regex, opts.nice) {
+            let item = match item {
+                CapOut::Group(caps, within, around) => {
+                    position.match_index += 1;
+                    position.count += 1;
+                    position.offset = at + json::raw_offset(&text[at..], within.offset);
+                    CapOut::Group(caps, position, around)
+                }
+                copy => copy,
+            };
+            if let Err(e) = item.output(caps, ctx, &mut edited) {
+                error = Some(e);
+                return None;
+            }
+        }
+        Some(edited)
+    })?;
+    if let Some(e) = error {
+        return Err(e);
+    }
+    writer.write_all(out_str.as_bytes())
+}
+
+/// A search which gave up part way is an error rather than no match,
+/// checked before anything is written back.
+fn search_failed(regex: &dyn Matcher) -> io::Result<()> {
+    match regex.take_error() {
+        Option::None => Ok(()),
+        Option::Some(e) => Err(io::Error::other(format!("the regex couldn't be matched: {}", e))),
     }
-    Ok(())
 }
 
 pub trait MyTrait: Write {
@@ -87,36 +263,184 @@ impl MyTrait for std::io::Stderr {
     }
 }
 
-fn do_streamable<R,W>(
+/// writes the header row of a delimited output, if there is one
+fn write_header<W: Write + ?Sized>(header: Option<&str>, term: &[u8], writer: &mut W) -> io::Result<()> {
+    match header {
+        Option::None => Ok(()),
+        Option::Some(header) => {
+            writer.write_all(header.as_bytes())?;
+            writer.write_all(term)
+        }
+    }
+}
+
+fn do_lines<R,W>(
+    opts: &InitialFlagOptions,
     reader: BufferedReader<R>,
     writer: &mut W,
     term: &'static [u8],
-    nice: bool, 
-    regex: &Regex,
+    regex: &dyn Matcher,
     caps: &[CapGroup<'_>],
+    ctx: &Context<'_>,
 ) -> io::Result<()>
 where
     R: Read,
-    W: Write,
+    W: Write + ?Sized,
 {
-    for res in reader {
+    match opts.columns {
+        Option::Some(_) => do_fields(opts, reader, writer, term, regex, caps, ctx),
+        Option::None => {
+            // every key of `--literals` on a line is replaced
+            let every_match = opts.literals.is_some();
+            do_streamable(reader, writer, term, opts.nice, every_match, |_| Some((regex, caps)), ctx)
+        }
+    }
+}
+
+/// Field mode, each line is split into fields and only the
+/// fields listed in `opts.columns` are matched and rewritten.
+fn do_fields<R,W>(
+    opts: &InitialFlagOptions,
+    mut reader: BufferedReader<R>,
+    writer: &mut W,
+    term: &'static [u8],
+    regex: &dyn Matcher,
+    caps: &[CapGroup<'_>],
+    ctx: &Context<'_>,
+) -> io::Result<()>
+where
+    R: Read,
+    W: Write + ?Sized,
+{
+    let (nice, delimiter) = (opts.nice, opts.delimiter);
+    let columns = opts.columns.as_deref().unwrap_or(&[]);
+    let mut out = String::with_capacity(4096);
+    let mut edited = String::with_capacity(256);
+    // a chunk of a larger input starts part way through it
+    let mut position = ctx.position;
+    let mut line_start = ctx.position.offset;
+    while let Some(res) = reader.next_line() {
         let (line,eol) = res?;
-        match regex.captures(&line) {
-            Option::None => {
-                if nice {
-                    writer.write_all(line.as_bytes())?;
-                    if eol {
-                        writer.write_all(term)?;
-                    }
+        let (raw,values): (Vec<&str>,Vec<_>) = split_record(line, delimiter).into_iter().unzip();
+        let mut ctx = Context { fields: &values, ..*ctx };
+        let mut matched = false;
+        position.line += 1;
+        position.match_index = 0;
+        out.clear();
+        for (pos,(raw,value)) in raw.iter().zip(values.iter()).enumerate() {
+            if pos != 0 {
+                out.push(delimiter);
+            }
+            let c = if columns.contains(&(pos + 1)) { regex.captures_from(value, 0) } else { None };
+            match c {
+                Option::None => out.push_str(raw),
+                Option::Some(ref c) => {
+                    matched = true;
+                    position.match_index += 1;
+                    position.count += 1;
+                    let (start, end) = match c.get(0) {
+                        Option::None => (0, 0),
+                        Option::Some(m) => (m.start(), m.end()),
+                    };
+                    position.offset = line_start + (raw.as_ptr() as usize - line.as_ptr() as usize) + delimited::raw_offset(raw, start);
+                    ctx.position = position;
+                    ctx.around = Around { before: &value[..start], after: &value[end..] };
+                    edited.clear();
+                    CapGroup::output(caps, c, &ctx, &mut edited)?;
+                    quote_field(&edited, delimiter, &mut out);
                 }
+            };
+        }
+        if matched || nice {
+            writer.write_all(out.as_bytes())?;
+            if eol {
+                writer.write_all(term)?;
             }
-            Option::Some(ref c) => {
-                CapGroup::steam_output(caps, c, writer)?;
-                if eol {
-                    writer.write_all(term)?;
+        }
+        line_start += line.len() + if eol { term.len() } else { 0 };
+    }
+    Ok(())
+}
+
+/// `select` picks the regex and format string for a line,
+/// a line it picks nothing for doesn't match. A matching line is
+/// replaced by the format string, or with `every_match` each match
+/// within the line is.
+fn do_streamable<'r,'c,R,W,F>(
+    mut reader: BufferedReader<R>,
+    writer: &mut W,
+    term: &'static [u8],
+    nice: bool,
+    every_match: bool,
+    select: F,
+    ctx: &Context<'_>,
+) -> io::Result<()>
+where
+    R: Read,
+    W: Write + ?Sized,
+    'c: 'r,
+    F: Fn(&str) -> Option<(&'r dyn Matcher, &'r [CapGroup<'c>])>,
+{
+    // a chunk of a larger input starts part way through it
+    let mut position = ctx.position;
+    let mut line_start = ctx.position.offset;
+    while let Some(res) = reader.next_line() {
+        let (line,eol) = res?;
+        position.line += 1;
+        let line_len = line.len() + if eol { term.len() } else { 0 };
+        let matched = match select(line) {
+            Option::Some((regex, caps)) if every_match => {
+                let mut matched = false;
+                for item in CapIter::new(line, regex, nice) {
+                    let item = match item {
+                        CapOut::Group(c, within, around) => {
+                            matched = true;
+                            position.match_index = within.match_index;
+                            position.count += 1;
+                            position.offset = line_start + within.offset;
+                            CapOut::Group(c, position, around)
+                        }
+                        copy => copy,
+                    };
+                    item.stream_output(caps, ctx, writer)?;
                 }
+                matched
             }
+            selected => match selected.and_then(|(regex, caps)| Some((regex.captures_from(line, 0)?, caps))) {
+                Option::None => {
+                    if nice {
+                        writer.write_all(line.as_bytes())?;
+                    }
+                    false
+                }
+                Option::Some((ref c, caps)) => {
+                    position.match_index = 1;
+                    position.count += 1;
+                    let (start, end) = match c.get(0) {
+                        Option::None => (0, 0),
+                        Option::Some(m) => (m.start(), m.end()),
+                    };
+                    position.offset = line_start + start;
+                    let around = Around { before: &line[..start], after: &line[end..] };
+                    CapGroup::steam_output(caps, c, &Context { position, around, ..*ctx }, writer)?;
+                    true
+                }
+            },
         };
+        if (matched || nice) && eol {
+            writer.write_all(term)?;
+        }
+        line_start += line_len;
     }
     Ok(())
 }
+
+/// Literal mode, every key of `literals` is matched in one pass
+/// instead of a regex and written as its value.
+pub fn do_literals(opts: &InitialFlagOptions, literals: &Literals, stack: &[String]) -> io::Result<()> {
+    let caps = CapGroup::build_groups("%<0:map>");
+    run(opts, stack, |stack, input, _, writer| {
+        let ctx = context(opts, Some(literals.mapping()), stack);
+        render_work(opts, input, literals, &caps, None, &ctx, writer)
+    })
+}